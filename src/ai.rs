@@ -0,0 +1,282 @@
+use std::collections::VecDeque;
+use std::time::Duration;
+
+use bevy::{prelude::*, utils::HashMap};
+
+use crate::animation::*;
+use crate::class::*;
+use crate::combat::*;
+use crate::input::*;
+use crate::item::*;
+use crate::map::*;
+use crate::pathing::*;
+use crate::replay::*;
+use crate::turn::*;
+use crate::unit::*;
+
+///Below this percent of max health an AI unit runs away instead of fighting.
+pub const RETREAT_HEALTH_PERCENT: u32 = 30;
+
+///How long the AI waits after each unit finishes so the player can follow along.
+const AI_STEP_PAUSE: Duration = Duration::from_millis(400);
+
+/// What an AI unit decided to do this turn.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct AiPlan
+{
+    pub dest: Location,
+    pub target: Option<Entity>,
+}
+
+/// A unit the AI could attack.
+#[derive(Clone, Copy, Debug)]
+pub struct AiTarget
+{
+    pub unit: Entity,
+    pub loc: Location,
+    pub health: u32,
+}
+
+///Reachable tiles the unit can end its move on, cheapest first. Sorted so the AI always makes the same choice.
+fn sorted_stops(reachable: &HashMap<Location, PathNode>, can_stand: impl Fn(Location) -> bool) -> Vec<(Location, f32)>
+{
+    let mut stops = reachable.iter()
+        .filter(|(loc, _)| can_stand(**loc))
+        .map(|(loc, node)| (*loc, node.cost))
+        .collect::<Vec<_>>();
+    stops.sort_by(|a, b| a.1.total_cmp(&b.1).then((a.0.1, a.0.0).cmp(&(b.0.1, b.0.0))));
+    stops
+}
+
+///Pick a move and an action for one AI unit.
+///
+///Units low on health retreat to the reachable tile furthest from every target. Otherwise they attack
+///whichever target they can reach most cheaply, preferring weaker targets on ties, and hold position if nothing is in reach.
+pub fn plan_turn
+(
+    loc: Location,
+    health: &Health,
+    reachable: &HashMap<Location, PathNode>,
+    can_stand: impl Fn(Location) -> bool,
+    targets: &[AiTarget],
+    (min, max): (usize, usize)
+) -> AiPlan
+{
+    let stops = sorted_stops(reachable, |at| at == loc || can_stand(at));
+
+    if health.current * 100 < health.max * RETREAT_HEALTH_PERCENT && !targets.is_empty()
+    {
+        let danger = |at: Location| targets.iter().map(|target| distance(at, target.loc)).min().unwrap_or(0);
+        let dest = stops.iter()
+            .map(|(at, _)| *at)
+            .fold(loc, |best, at| if danger(at) > danger(best) {at} else {best});
+        return AiPlan{dest, target: None};
+    }
+
+    let mut best: Option<(f32, u32, Location, Entity)> = None;
+    for (at, cost) in &stops
+    {
+        for target in targets
+        {
+            if !(min..=max).contains(&distance(*at, target.loc))
+            {
+                continue;
+            }
+            let better = match best
+            {
+                None => true,
+                Some((best_cost, best_hp, _, _)) => *cost < best_cost || (*cost == best_cost && target.health < best_hp),
+            };
+            if better
+            {
+                best = Some((*cost, target.health, *at, target.unit));
+            }
+        }
+    }
+    match best
+    {
+        Some((_, _, dest, target)) => AiPlan{dest, target: Some(target)},
+        None => AiPlan{dest: loc, target: None},
+    }
+}
+
+enum AiStep
+{
+    Walking { unit: Entity, target: Option<Entity> },
+    Attacking { unit: Entity },
+    Pausing,
+}
+
+/// Units still waiting to act this AI phase and what the current one is doing.
+#[derive(Resource, Default)]
+pub struct AiTurn
+{
+    queue: VecDeque<Entity>,
+    step: Option<AiStep>,
+    pause: Timer,
+}
+
+///Queue up every non player unit to act.
+pub fn start_ai_phase
+(
+    mut ai_turn: ResMut<AiTurn>,
+    unit_qry: Query<(Entity, &Team), With<IsUnit>>
+)
+{
+    let mut queue = unit_qry.iter()
+        .filter(|(_, team)| team.0 != PLAYER_TEAM)
+        .map(|(unit, _)| unit)
+        .collect::<Vec<_>>();
+    queue.sort();
+    *ai_turn = AiTurn
+    {
+        queue: queue.into(),
+        step: None,
+        pause: Timer::new(AI_STEP_PAUSE, TimerMode::Once),
+    };
+}
+
+///Drive the AI phase one unit at a time: plan, walk, attack, pause, then hand control back to the player.
+pub fn run_ai_turn
+(
+    mut cmd: Commands,
+    time: Res<Time>,
+    mut ai_turn: ResMut<AiTurn>,
+    map_qry: Query<(&MapSize, &TileMap, &TileList, &UnitMap, &HeightMap)>,
+    unit_qry: Query<(Entity, &Location, &Movement, &Team, &Class, &Health, &Inventory, Has<WalkPath>), With<IsUnit>>,
+    team_qry: Query<&Team>,
+    mut attack_unit: EventWriter<AttackUnit>,
+    mut combat_finished: EventReader<CombatFinished>,
+    mut end_phase: EventWriter<EndPhase>,
+    id_qry: Query<&UnitID>,
+    mut log: ResMut<CommandLog>
+)
+{
+    let finished_combat = combat_finished.read().count() > 0;
+    match ai_turn.step
+    {
+        Some(AiStep::Walking{unit, target}) =>
+        {
+            if unit_qry.get(unit).is_ok_and(|(.., walking)| walking)
+            {
+                return;
+            }
+            ai_turn.step = match target
+            {
+                Some(defender) if unit_qry.contains(defender) =>
+                {
+                    attack_unit.send(AttackUnit{attacker: unit, defender});
+                    Some(AiStep::Attacking{unit})
+                },
+                _ =>
+                {
+                    cmd.entity(unit).insert(Acted);
+                    if let Ok(id) = id_qry.get(unit)
+                    {
+                        log.push(BattleCommand::Wait{unit: *id});
+                    }
+                    Some(AiStep::Pausing)
+                },
+            };
+            return;
+        },
+        Some(AiStep::Attacking{unit}) =>
+        {
+            if finished_combat
+            {
+                //The unit may have died to a counterattack.
+                if let Some(mut unit_cmd) = cmd.get_entity(unit)
+                {
+                    unit_cmd.insert(Acted);
+                }
+                ai_turn.step = Some(AiStep::Pausing);
+            }
+            return;
+        },
+        Some(AiStep::Pausing) =>
+        {
+            ai_turn.pause.tick(time.delta());
+            if !ai_turn.pause.finished()
+            {
+                return;
+            }
+            ai_turn.pause.reset();
+            ai_turn.step = None;
+        },
+        None => {},
+    }
+
+    //Units can die during the phase, so skip anything that's gone.
+    let Some(unit) = ai_turn.queue.pop_front() else
+    {
+        end_phase.send(EndPhase);
+        return;
+    };
+    let Ok((_, &loc, movement, team, class, health, inventory, _)) = unit_qry.get(unit) else {return};
+    let map = map_qry.single();
+    let unit_map = map.3;
+
+    let reachable = unit_reachable_tiles(loc, movement, team, class, map, &team_qry);
+    let mut targets = unit_qry.iter()
+        .filter(|(_, _, _, other_team, ..)| other_team.0 != team.0)
+        .map(|(target, &target_loc, _, _, _, target_hp, ..)| AiTarget{unit: target, loc: target_loc, health: target_hp.current})
+        .collect::<Vec<_>>();
+    targets.sort_by_key(|target| target.unit);
+    //A unit with nothing to fight with holds its ground.
+    let plan = match inventory.attack_range()
+    {
+        Some(range) => plan_turn(loc, health, &reachable, |at| unit_map.get(at).is_none(), &targets, range),
+        None => AiPlan{dest: loc, target: None},
+    };
+
+    if plan.dest != loc
+    {
+        if let Some(path) = path_through(&reachable, plan.dest)
+        {
+            cmd.entity(unit).insert(WalkPath::new(path, ClipId::Idle));
+        }
+    }
+    ai_turn.step = Some(AiStep::Walking{unit, target: plan.target});
+}
+
+#[cfg(test)]
+mod test
+{
+    use super::*;
+
+    fn target(raw: u32, loc: Location, health: u32) -> AiTarget
+    {
+        AiTarget{unit: Entity::from_raw(raw), loc, health}
+    }
+
+    #[test]
+    pub fn test_plan_attack()
+    {
+        let size = MapSize(6, 6);
+        let reachable = reachable_tiles(Location(0, 0), 3.0, &size, |_, _| 1.0, |_| false);
+        let full = Health{max: 20, current: 20, temp: 0};
+        let near = target(1, Location(2, 1), 20);
+        let weak = target(2, Location(1, 2), 5);
+        let far = target(3, Location(5, 5), 1);
+
+        //Both near targets can be hit after moving two tiles, so the weaker one is picked.
+        let plan = plan_turn(Location(0, 0), &full, &reachable, |_| true, &[near, weak, far], (1, 1));
+        assert_eq!(plan.target, Some(weak.unit));
+        assert_eq!(distance(plan.dest, weak.loc), 1);
+        assert!(reachable[&plan.dest].cost <= 2.0);
+
+        let plan = plan_turn(Location(0, 0), &full, &reachable, |_| true, &[far], (1, 1));
+        assert_eq!(plan, AiPlan{dest: Location(0, 0), target: None});
+    }
+
+    #[test]
+    pub fn test_plan_retreat()
+    {
+        let size = MapSize(6, 6);
+        let reachable = reachable_tiles(Location(2, 2), 2.0, &size, |_, _| 1.0, |_| false);
+        let hurt = Health{max: 20, current: 3, temp: 0};
+        let plan = plan_turn(Location(2, 2), &hurt, &reachable, |_| true, &[target(1, Location(3, 3), 20)], (1, 1));
+        assert_eq!(plan.target, None);
+        assert_eq!(distance(plan.dest, Location(3, 3)), 4);
+    }
+}
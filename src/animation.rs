@@ -0,0 +1,573 @@
+use std::fmt;
+
+use bevy::{
+    asset::{io::Reader, AssetLoader, AsyncReadExt, LoadContext},
+    prelude::*,
+    reflect::TypePath,
+    utils::HashMap,
+};
+use serde::Deserialize;
+
+/// How a clip carries on once its last frame has been shown.
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum PlayMode
+{
+    #[default] Loop,
+    ///Play through once, then switch to the clip's `then` clip or the library's fallback.
+    Once,
+    ///Play forwards, then backwards, without repeating the end frames.
+    PingPong,
+}
+
+/// A frame of a clip. Either a bare sheet index, or `(index, hold)` to show the frame for `hold` frame times.
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(untagged)]
+pub enum AnimationFrame
+{
+    Frame(usize),
+    Hold(usize, u32),
+}
+
+impl AnimationFrame
+{
+    pub fn index(&self) -> usize
+    {
+        match self
+        {
+            AnimationFrame::Frame(index) | AnimationFrame::Hold(index, _) => *index,
+        }
+    }
+
+    pub fn hold(&self) -> u32
+    {
+        match self
+        {
+            AnimationFrame::Frame(_) => 1,
+            AnimationFrame::Hold(_, hold) => *hold,
+        }
+    }
+}
+
+/// The clips a unit can play. Animation sets can only define these, so a misspelt clip fails when the set loads.
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum ClipId
+{
+    Idle,
+    Selected,
+    RunningLr,
+    RunningDown,
+    RunningUp,
+    Attack,
+    Hurt,
+}
+
+/// A single animation on a sprite sheet.
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+pub struct AnimationClip
+{
+    ///Sprite sheet frames, played in order.
+    pub frames: Vec<AnimationFrame>,
+    pub fps: f32,
+    #[serde(default)]
+    pub mode: PlayMode,
+    ///Clip to play after a `Once` clip ends, instead of the library's fallback.
+    #[serde(default)]
+    pub then: Option<ClipId>,
+}
+
+impl AnimationClip
+{
+    ///How long the given frame of the clip stays on screen, in seconds.
+    pub fn frame_secs(&self, frame: usize) -> f32
+    {
+        self.frames[frame].hold() as f32 / self.fps
+    }
+}
+
+/// A sprite sheet and the animations cut from it, as written in an `.anims.ron` file.
+#[derive(Deserialize, Debug)]
+pub struct AnimationSetData
+{
+    pub name: String,
+    ///Asset path of the sprite sheet image.
+    pub sheet: String,
+    ///Size of a single frame in pixels.
+    pub tile_size: (u32, u32),
+    pub columns: u32,
+    pub rows: u32,
+    pub animations: HashMap<ClipId, AnimationClip>,
+}
+
+impl AnimationSetData
+{
+    pub fn validate(&self) -> Result<(), AnimationLoadError>
+    {
+        let frame_count = (self.columns * self.rows) as usize;
+        for (clip_name, clip) in &self.animations
+        {
+            let clip_error = |problem| AnimationLoadError::BadClip{set: self.name.clone(), clip: *clip_name, problem};
+            if clip.frames.is_empty()
+            {
+                return Err(clip_error("has no frames".into()));
+            }
+            if !(clip.fps > 0.0)
+            {
+                return Err(clip_error(format!("has an fps of {}", clip.fps)));
+            }
+            if let Some(frame) = clip.frames.iter().find(|frame| frame.index() >= frame_count)
+            {
+                return Err(clip_error(format!("uses frame {} but the sheet only has {}", frame.index(), frame_count)));
+            }
+            if clip.frames.iter().any(|frame| frame.hold() == 0)
+            {
+                return Err(clip_error("holds a frame for 0 frame times".into()));
+            }
+            if let Some(then) = clip.then.filter(|then| !self.animations.contains_key(then))
+            {
+                return Err(clip_error(format!("is followed by {:?}, which doesn't exist", then)));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A loaded animation set, with handles to its sprite sheet and atlas layout.
+#[derive(Debug)]
+pub struct AnimationSet
+{
+    pub name: String,
+    pub image: Handle<Image>,
+    pub layout: Handle<TextureAtlasLayout>,
+    pub animations: HashMap<ClipId, AnimationClip>,
+}
+
+/// Every animation set in an `.anims.ron` file. Units pick one by name.
+#[derive(Asset, TypePath, Debug)]
+pub struct AnimationSetList(pub Vec<AnimationSet>);
+
+impl AnimationSetList
+{
+    pub fn get(&self, name: &str) -> Option<&AnimationSet>
+    {
+        self.0.iter().find(|set| set.name == name)
+    }
+}
+
+#[derive(Debug)]
+pub enum AnimationLoadError
+{
+    Io(std::io::Error),
+    Ron(ron::error::SpannedError),
+    DuplicateSet(String),
+    BadClip{set: String, clip: ClipId, problem: String},
+}
+
+impl fmt::Display for AnimationLoadError
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result
+    {
+        match self
+        {
+            AnimationLoadError::Io(err) => write!(f, "could not read animation file: {}", err),
+            AnimationLoadError::Ron(err) => write!(f, "could not parse animation file: {}", err),
+            AnimationLoadError::DuplicateSet(name) => write!(f, "animation set {} is defined more than once", name),
+            AnimationLoadError::BadClip{set, clip, problem} => write!(f, "animation {:?} in set {} {}", clip, set, problem),
+        }
+    }
+}
+
+impl std::error::Error for AnimationLoadError {}
+
+impl From<std::io::Error> for AnimationLoadError
+{
+    fn from(err: std::io::Error) -> Self
+    {
+        AnimationLoadError::Io(err)
+    }
+}
+
+impl From<ron::error::SpannedError> for AnimationLoadError
+{
+    fn from(err: ron::error::SpannedError) -> Self
+    {
+        AnimationLoadError::Ron(err)
+    }
+}
+
+#[derive(Default)]
+pub struct AnimationSetLoader;
+
+impl AssetLoader for AnimationSetLoader
+{
+    type Asset = AnimationSetList;
+    type Settings = ();
+    type Error = AnimationLoadError;
+
+    async fn load<'a>
+    (
+        &'a self,
+        reader: &'a mut Reader<'_>,
+        _settings: &'a (),
+        load_context: &'a mut LoadContext<'_>,
+    ) -> Result<AnimationSetList, AnimationLoadError>
+    {
+        let mut bytes = Vec::new();
+        reader.read_to_end(&mut bytes).await?;
+        let sets = ron::de::from_bytes::<Vec<AnimationSetData>>(&bytes)?;
+        let mut out: Vec<AnimationSet> = Vec::with_capacity(sets.len());
+        for set in sets
+        {
+            if out.iter().any(|other| other.name == set.name)
+            {
+                return Err(AnimationLoadError::DuplicateSet(set.name));
+            }
+            set.validate()?;
+            //The sheet is loaded as a dependency, so the list only counts as loaded once every sheet is.
+            let image = load_context.load(set.sheet.clone());
+            let layout = load_context.add_labeled_asset
+            (
+                format!("{}_layout", set.name),
+                TextureAtlasLayout::from_grid(UVec2::new(set.tile_size.0, set.tile_size.1), set.columns, set.rows, None, None)
+            );
+            out.push(AnimationSet{name: set.name, image, layout, animations: set.animations});
+        }
+        Ok(AnimationSetList(out))
+    }
+
+    fn extensions(&self) -> &[&str]
+    {
+        &["anims.ron"]
+    }
+}
+
+///Handle to the animation sets units can use.
+#[derive(Resource)]
+pub struct AnimationAsset
+{
+    pub sets: Handle<AnimationSetList>,
+}
+
+///Start loading the animation sets. Sprite3d needs the sheets loaded before any units are spawned.
+pub fn init_animation_asset
+(
+    mut cmd: Commands,
+    asset_server: Res<AssetServer>
+)
+{
+    cmd.insert_resource(AnimationAsset{sets: asset_server.load("animations/units.anims.ron")});
+}
+
+/// A library of animations.
+#[derive(Component)]
+pub struct AnimationLibrary 
+{
+    animations: HashMap<ClipId, AnimationClip>,
+    current_animation: ClipId,
+    current_frame: usize,
+    ///Seconds the current frame has been shown for.
+    elapsed: f32,
+    ///True while a ping-pong clip is playing backwards.
+    reversed: bool,
+    ///Clip played once a `Once` clip without a `then` ends.
+    fallback: ClipId,
+}
+
+impl AnimationLibrary
+{
+    fn builder() -> AnimationLibraryBuilder
+    { 
+        AnimationLibraryBuilder::default()
+    }
+
+    ///Switch to another clip and start it from its first frame. Clips the library doesn't have fall back to idle.
+    pub fn set_animation(&mut self, animation: ClipId)
+    {
+        if animation == self.current_animation
+        {
+            return;
+        }
+        self.current_animation = if self.animations.contains_key(&animation)
+        {
+            animation
+        } else
+        {
+            println!("Missing {:?} animation! Playing idle instead.", animation);
+            ClipId::Idle
+        };
+        self.current_frame = 0;
+        self.elapsed = 0.0;
+        self.reversed = false;
+    }
+
+    pub fn current_animation(&self) -> ClipId
+    {
+        self.current_animation
+    }
+
+    fn current_clip(&self) -> &AnimationClip
+    {
+        //build makes sure the current clip always exists.
+        &self.animations[&self.current_animation]
+    }
+
+    ///The sprite sheet index that should be on screen.
+    pub fn current_index(&self) -> usize
+    {
+        self.current_clip().frames[self.current_frame].index()
+    }
+
+    ///Move the animation forward by `delta` seconds. Returns the clip if a `Once` clip finished.
+    pub fn advance(&mut self, delta: f32) -> Option<ClipId>
+    {
+        let mut finished = None;
+        self.elapsed += delta;
+        loop
+        {
+            let clip = self.current_clip();
+            let (frame_secs, last, mode, then) = (clip.frame_secs(self.current_frame), clip.frames.len() - 1, clip.mode, clip.then);
+            if self.elapsed < frame_secs
+            {
+                return finished;
+            }
+            self.elapsed -= frame_secs;
+
+            match mode
+            {
+                PlayMode::Loop => self.current_frame = (self.current_frame + 1) % (last + 1),
+                PlayMode::PingPong if last == 0 => (),
+                PlayMode::PingPong =>
+                {
+                    if self.current_frame == last
+                    {
+                        self.reversed = true;
+                    } else if self.current_frame == 0
+                    {
+                        self.reversed = false;
+                    }
+                    if self.reversed {self.current_frame -= 1} else {self.current_frame += 1}
+                },
+                PlayMode::Once if self.current_frame < last => self.current_frame += 1,
+                PlayMode::Once =>
+                {
+                    finished = Some(self.current_animation);
+                    let next = then.unwrap_or(self.fallback);
+                    let elapsed = self.elapsed;
+                    self.set_animation(next);
+                    //A clip can be followed by itself, which set_animation treats as no switch at all.
+                    self.current_frame = 0;
+                    self.elapsed = elapsed;
+                },
+            }
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct AnimationLibraryBuilder
+{
+    animations: HashMap<ClipId, AnimationClip>,
+    current_animation: Option<ClipId>,
+    current_frame: usize,
+    fallback: Option<ClipId>,
+}
+
+impl AnimationLibraryBuilder
+{
+    pub fn new() -> AnimationLibraryBuilder
+    {
+        AnimationLibraryBuilder
+        {
+            animations: HashMap::new(),
+            current_animation: None,
+            current_frame: 0,
+            fallback: None,
+        }
+    }
+    ///Pass a hashmap of animations to this to fill the animation library automatically.
+    pub fn set_animations(mut self, hash_map: HashMap<ClipId, AnimationClip>) -> AnimationLibraryBuilder
+    {
+        for (name, clip) in hash_map
+        {
+            self.animations.insert(name, clip);
+        };
+        self
+    }
+    ///Set the current animation. Defaults to idle.
+    pub fn set_animation(mut self, clip: ClipId) -> AnimationLibraryBuilder
+    {
+        self.current_animation = Some(clip);
+        self
+    }
+    ///Set the frame of the current animation, relative to the animation itself, not the index of the complete sprite sheet.
+    pub fn set_frame(mut self, frame: usize) -> AnimationLibraryBuilder
+    {
+        self.current_frame = frame;
+        self
+    }
+    ///Set the animation played after a one-shot clip ends. Defaults to idle.
+    pub fn set_fallback(mut self, clip: ClipId) -> AnimationLibraryBuilder
+    {
+        self.fallback = Some(clip);
+        self
+    }
+    ///Every clip the library is asked to play is checked here. Missing clips are swapped for idle, and a single frame idle is added if the set has none.
+    pub fn build(mut self) -> AnimationLibrary
+    {
+        self.animations.entry(ClipId::Idle)
+            .or_insert_with(|| AnimationClip{frames: vec![AnimationFrame::Frame(0)], fps: 5.0, mode: PlayMode::Loop, then: None});
+        let mut check = |clip: Option<ClipId>, what: &str|
+        {
+            match clip
+            {
+                Some(clip) if !self.animations.contains_key(&clip) =>
+                {
+                    println!("Missing {:?} animation for the {}! Using idle instead.", clip, what);
+                    ClipId::Idle
+                },
+                clip => clip.unwrap_or(ClipId::Idle),
+            }
+        };
+        let current_animation = check(self.current_animation, "starting clip");
+        let fallback = check(self.fallback, "fallback");
+        let current_frame = if self.current_frame < self.animations[&current_animation].frames.len() {self.current_frame} else {0};
+        AnimationLibrary
+        {
+            animations: self.animations,
+            current_animation,
+            current_frame,
+            elapsed: 0.0,
+            reversed: false,
+            fallback,
+        }
+    }
+}
+
+pub fn animate_sprites
+(
+    time: Res<Time>,
+    mut query: Query<(&mut AnimationLibrary, &mut TextureAtlas)>,
+) 
+{
+    for (mut animation, mut atlas) in query.iter_mut() 
+    {
+        animation.advance(time.delta_seconds());
+        let index = animation.current_index();
+        if atlas.index != index
+        {
+            atlas.index = index;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test
+{
+    use super::*;
+
+    #[test]
+    pub fn test_validate_animation_set()
+    {
+        let mut set = AnimationSetData
+        {
+            name: "Test".into(),
+            sheet: "test.png".into(),
+            tile_size: (32, 32),
+            columns: 2,
+            rows: 2,
+            animations: HashMap::new(),
+        };
+        set.animations.insert(ClipId::Idle, clip(vec![AnimationFrame::Frame(0), AnimationFrame::Hold(3, 4)], PlayMode::Loop, None));
+        assert!(set.validate().is_ok());
+
+        set.animations.insert(ClipId::RunningLr, clip(vec![AnimationFrame::Frame(2), AnimationFrame::Frame(4)], PlayMode::Loop, None));
+        assert!(matches!(set.validate(), Err(AnimationLoadError::BadClip{clip: ClipId::RunningLr, ..})));
+
+        set.animations.insert(ClipId::RunningLr, AnimationClip{fps: 0.0, ..clip(vec![AnimationFrame::Frame(2)], PlayMode::Loop, None)});
+        assert!(set.validate().is_err());
+
+        set.animations.insert(ClipId::RunningLr, clip(vec![AnimationFrame::Frame(2)], PlayMode::Once, Some(ClipId::Attack)));
+        assert!(set.validate().is_err());
+    }
+
+    fn clip(frames: Vec<AnimationFrame>, mode: PlayMode, then: Option<ClipId>) -> AnimationClip
+    {
+        AnimationClip{frames, fps: 10.0, mode, then}
+    }
+
+    #[test]
+    pub fn test_parse_frames()
+    {
+        let clip: AnimationClip = ron::de::from_str("(frames: [3, (4, 5)], fps: 12.0, mode: Once, then: Some(running_lr))").unwrap();
+        assert_eq!(clip.frames, vec![AnimationFrame::Frame(3), AnimationFrame::Hold(4, 5)]);
+        assert_eq!(clip.mode, PlayMode::Once);
+        assert_eq!(clip.then, Some(ClipId::RunningLr));
+        let clips: Result<HashMap<ClipId, AnimationClip>, _> = ron::de::from_str("{walk: (frames: [0], fps: 1.0)}");
+        assert!(clips.is_err());
+    }
+
+    #[test]
+    pub fn test_advance_holds_and_modes()
+    {
+        use AnimationFrame::*;
+        let mut clips = HashMap::new();
+        clips.insert(ClipId::Idle, clip(vec![Hold(0, 3), Frame(1)], PlayMode::Loop, None));
+        clips.insert(ClipId::Selected, clip(vec![Frame(4), Frame(5), Frame(6)], PlayMode::PingPong, None));
+        clips.insert(ClipId::Attack, clip(vec![Frame(8), Frame(9)], PlayMode::Once, Some(ClipId::Selected)));
+        clips.insert(ClipId::Hurt, clip(vec![Frame(10)], PlayMode::Once, None));
+        let mut lib = AnimationLibraryBuilder::new()
+            .set_animations(clips)
+            .build();
+
+        //Frame 0 is held for 3 frame times.
+        lib.advance(0.25);
+        assert_eq!(lib.current_index(), 0);
+        lib.advance(0.1);
+        assert_eq!(lib.current_index(), 1);
+        lib.advance(0.1);
+        assert_eq!(lib.current_index(), 0);
+
+        lib = AnimationLibraryBuilder::new().set_animations(lib.animations).set_animation(ClipId::Selected).build();
+        let mut seen = vec![lib.current_index()];
+        for _ in 0..5
+        {
+            lib.advance(0.1001);
+            seen.push(lib.current_index());
+        }
+        assert_eq!(seen, vec![4, 5, 6, 5, 4, 5]);
+
+        lib = AnimationLibraryBuilder::new().set_animations(lib.animations).set_animation(ClipId::Attack).build();
+        assert_eq!(lib.advance(0.1001), None);
+        assert_eq!(lib.advance(0.1), Some(ClipId::Attack));
+        assert_eq!(lib.current_animation(), ClipId::Selected);
+
+        lib.set_animation(ClipId::Hurt);
+        assert_eq!(lib.advance(0.1001), Some(ClipId::Hurt));
+        assert_eq!(lib.current_animation(), ClipId::Idle);
+    }
+
+    #[test]
+    pub fn test_switch_and_missing_clips()
+    {
+        use AnimationFrame::*;
+        let mut clips = HashMap::new();
+        clips.insert(ClipId::Selected, clip(vec![Frame(4), Frame(5), Frame(6)], PlayMode::Loop, None));
+        clips.insert(ClipId::RunningUp, clip(vec![Frame(7)], PlayMode::Loop, None));
+        let mut lib = AnimationLibraryBuilder::new()
+            .set_animations(clips)
+            .set_animation(ClipId::Attack)
+            .set_frame(2)
+            .build();
+        //The set has no idle or attack, so a one frame idle is made up and played instead.
+        assert_eq!(lib.current_animation(), ClipId::Idle);
+        assert_eq!(lib.current_index(), 0);
+
+        lib.set_animation(ClipId::Selected);
+        lib.advance(0.25);
+        assert_eq!(lib.current_index(), 6);
+        lib.set_animation(ClipId::RunningUp);
+        assert_eq!(lib.current_index(), 7);
+        lib.set_animation(ClipId::Hurt);
+        assert_eq!(lib.current_animation(), ClipId::Idle);
+    }
+}
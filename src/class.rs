@@ -0,0 +1,293 @@
+use std::fmt;
+
+use bevy::{
+    asset::{io::Reader, AssetLoader, AsyncReadExt, LoadContext},
+    prelude::*,
+    reflect::TypePath,
+    utils::HashMap,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::map::*;
+
+/// A unit's base stats.
+#[derive(Component, Deserialize, Serialize, Clone, Copy, Debug, Default, PartialEq)]
+pub struct Stats
+{
+    pub strength: u32,
+    pub magic: u32,
+    pub skill: u32,
+    pub speed: u32,
+    pub luck: u32,
+    pub defense: u32,
+    pub resistance: u32,
+    pub constitution: u32,
+}
+
+impl Stats
+{
+    ///Short names of the stats, in the same order as `values`.
+    pub const NAMES: [&'static str; 8] = ["Str", "Mag", "Skl", "Spd", "Lck", "Def", "Res", "Con"];
+
+    pub fn values(&self) -> [u32; 8]
+    {
+        [self.strength, self.magic, self.skill, self.speed, self.luck, self.defense, self.resistance, self.constitution]
+    }
+
+    pub fn from_values(values: [u32; 8]) -> Stats
+    {
+        let [strength, magic, skill, speed, luck, defense, resistance, constitution] = values;
+        Stats{strength, magic, skill, speed, luck, defense, resistance, constitution}
+    }
+
+    ///Stat by stat sum of both.
+    pub fn plus(&self, other: &Stats) -> Stats
+    {
+        let (a, b) = (self.values(), other.values());
+        Stats::from_values(std::array::from_fn(|i| a[i] + b[i]))
+    }
+}
+
+/// Percent chance of each stat going up when a unit levels up.
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, Default, PartialEq)]
+pub struct Growths
+{
+    pub hp: u32,
+    pub stats: Stats,
+}
+
+impl Growths
+{
+    pub fn plus(&self, other: &Growths) -> Growths
+    {
+        Growths{hp: self.hp + other.hp, stats: self.stats.plus(&other.stats)}
+    }
+}
+
+///Highest max health a class can reach when it doesn't set its own cap.
+pub const DEFAULT_HP_CAP: u32 = 60;
+
+/// Highest each stat, and max health, can be raised to by leveling up.
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq)]
+pub struct Caps
+{
+    #[serde(default = "default_hp_cap")]
+    pub hp: u32,
+    pub stats: Stats,
+}
+
+impl Default for Caps
+{
+    fn default() -> Self
+    {
+        Caps{hp: DEFAULT_HP_CAP, stats: Stats::from_values([20; 8])}
+    }
+}
+
+fn default_hp_cap() -> u32
+{
+    DEFAULT_HP_CAP
+}
+
+/// How a class gets around the map.
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum MovementType
+{
+    #[default] Foot,
+    Armored,
+    Mounted,
+    Flying,
+}
+
+/// A class definition from `classes.ron`.
+#[derive(Deserialize, Clone, Debug)]
+pub struct ClassData
+{
+    pub name: String,
+    pub base: Stats,
+    pub movement: f32,
+    #[serde(default)]
+    pub movement_type: MovementType,
+    ///Multiplier on a tile's mv_cost, keyed by tile name. Tiles not listed cost their normal amount.
+    #[serde(default)]
+    pub terrain_costs: HashMap<String, f32>,
+    ///Name of the animation set in `anims.ron` that units of this class are drawn with.
+    pub animation_set: String,
+    ///Added to a unit's personal growths when it levels up.
+    #[serde(default)]
+    pub growths: Growths,
+    ///Defaults to `DEFAULT_HP_CAP` health and 20 for every stat.
+    #[serde(default)]
+    pub caps: Caps,
+}
+
+impl ClassData
+{
+    ///What it costs a unit of this class to step onto the tile.
+    pub fn terrain_cost(&self, tile: &Tile) -> f32
+    {
+        tile.mv_cost * self.terrain_costs.get(&tile.name).copied().unwrap_or(1.0)
+    }
+}
+
+/// Every class the game knows about.
+#[derive(Asset, TypePath, Deserialize, Debug)]
+pub struct ClassList(pub Vec<ClassData>);
+
+impl ClassList
+{
+    pub fn get(&self, name: &str) -> Option<&ClassData>
+    {
+        self.0.iter().find(|class| class.name == name)
+    }
+
+    pub fn validate(&self) -> Result<(), ClassLoadError>
+    {
+        for (i, class) in self.0.iter().enumerate()
+        {
+            if self.0[..i].iter().any(|other| other.name == class.name)
+            {
+                return Err(ClassLoadError::DuplicateClass(class.name.clone()));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The class of a unit.
+#[derive(Component, Clone, Debug)]
+pub struct Class(pub ClassData);
+
+#[derive(Debug)]
+pub enum ClassLoadError
+{
+    Io(std::io::Error),
+    Ron(ron::error::SpannedError),
+    DuplicateClass(String),
+}
+
+impl fmt::Display for ClassLoadError
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result
+    {
+        match self
+        {
+            ClassLoadError::Io(err) => write!(f, "could not read class file: {}", err),
+            ClassLoadError::Ron(err) => write!(f, "could not parse class file: {}", err),
+            ClassLoadError::DuplicateClass(name) => write!(f, "class {} is defined more than once", name),
+        }
+    }
+}
+
+impl std::error::Error for ClassLoadError {}
+
+impl From<std::io::Error> for ClassLoadError
+{
+    fn from(err: std::io::Error) -> Self
+    {
+        ClassLoadError::Io(err)
+    }
+}
+
+impl From<ron::error::SpannedError> for ClassLoadError
+{
+    fn from(err: ron::error::SpannedError) -> Self
+    {
+        ClassLoadError::Ron(err)
+    }
+}
+
+#[derive(Default)]
+pub struct ClassListLoader;
+
+impl AssetLoader for ClassListLoader
+{
+    type Asset = ClassList;
+    type Settings = ();
+    type Error = ClassLoadError;
+
+    async fn load<'a>
+    (
+        &'a self,
+        reader: &'a mut Reader<'_>,
+        _settings: &'a (),
+        _load_context: &'a mut LoadContext<'_>,
+    ) -> Result<ClassList, ClassLoadError>
+    {
+        let mut bytes = Vec::new();
+        reader.read_to_end(&mut bytes).await?;
+        let classes = ron::de::from_bytes::<ClassList>(&bytes)?;
+        classes.validate()?;
+        Ok(classes)
+    }
+
+    fn extensions(&self) -> &[&str]
+    {
+        &["classes.ron"]
+    }
+}
+
+///Handle to the class list used by the current battle.
+#[derive(Resource)]
+pub struct ClassAsset
+{
+    pub classes: Handle<ClassList>,
+}
+
+///Start loading the class list. Needs to finish before any units are spawned.
+pub fn init_class_asset
+(
+    mut cmd: Commands,
+    asset_server: Res<AssetServer>
+)
+{
+    cmd.insert_resource(ClassAsset{classes: asset_server.load("classes/base.classes.ron")});
+}
+
+#[cfg(test)]
+mod test
+{
+    use super::*;
+    use crate::map_data::TileData;
+
+    fn class(name: &str, terrain_costs: &[(&str, f32)]) -> ClassData
+    {
+        ClassData
+        {
+            name: name.into(),
+            base: Stats::default(),
+            movement: 5.0,
+            movement_type: MovementType::Foot,
+            terrain_costs: terrain_costs.iter().map(|(tile, cost)| (tile.to_string(), *cost)).collect(),
+            animation_set: "Placeholder".into(),
+            growths: Growths::default(),
+            caps: Caps::default(),
+        }
+    }
+
+    fn tile(name: &str, mv_cost: f32) -> Tile
+    {
+        Tile::from(&TileData{id: 0, name: name.into(), mv_cost, color: (0.0, 0.0, 0.0), defense: 0, avoid: 0, heal: 0})
+    }
+
+    #[test]
+    pub fn test_validate_class_list()
+    {
+        assert!(ClassList(vec![class("Lord", &[]), class("Knight", &[])]).validate().is_ok());
+        assert!(matches!(
+            ClassList(vec![class("Lord", &[]), class("Knight", &[]), class("Lord", &[])]).validate(),
+            Err(ClassLoadError::DuplicateClass(name)) if name == "Lord"
+        ));
+    }
+
+    #[test]
+    pub fn test_terrain_cost()
+    {
+        let cavalier = class("Cavalier", &[("Forest", 2.0), ("Sand", 1.5)]);
+        assert_eq!(cavalier.terrain_cost(&tile("Forest", 2.0)), 4.0);
+        assert_eq!(cavalier.terrain_cost(&tile("Sand", 2.0)), 3.0);
+        //Tiles without an entry cost what the tile says.
+        assert_eq!(cavalier.terrain_cost(&tile("Plain", 1.0)), 1.0);
+        assert_eq!(class("Lord", &[]).terrain_cost(&tile("Forest", 2.0)), 2.0);
+    }
+}
@@ -0,0 +1,462 @@
+use bevy::prelude::*;
+use crate::class::*;
+use crate::item::*;
+use crate::level::*;
+use crate::map::*;
+use crate::shared::*;
+use crate::unit::*;
+use crate::objective::*;
+use crate::rng::*;
+
+///How much faster a unit needs to be than its foe to strike twice.
+pub const DOUBLE_ATTACK_SPEED: u32 = 4;
+
+///How much damage a critical hit multiplies by.
+pub const CRIT_MULTIPLIER: u32 = 3;
+
+///Hit bonus for each height level a unit stands above its foe.
+pub const HEIGHT_HIT_BONUS: i32 = 10;
+
+///Hit rate a unit has before skill and luck are added. An equipped weapon's hit replaces it.
+pub const BASE_HIT: i32 = 70;
+
+/// The numbers one side of a fight brings into combat.
+#[derive(Clone, Copy, Debug)]
+pub struct CombatStats
+{
+    pub attack: u32,
+    pub defense: u32,
+    pub hit: i32,
+    pub avoid: i32,
+    pub crit: i32,
+    pub crit_avoid: i32,
+    pub speed: u32,
+}
+
+impl Default for CombatStats
+{
+    fn default() -> Self
+    {
+        CombatStats
+        {
+            attack: 7,
+            defense: 3,
+            hit: 80,
+            avoid: 20,
+            crit: 5,
+            crit_avoid: 0,
+            speed: 5,
+        }
+    }
+}
+
+impl CombatStats
+{
+    pub fn from_stats(stats: &Stats) -> CombatStats
+    {
+        CombatStats
+        {
+            attack: stats.strength,
+            defense: stats.defense,
+            hit: BASE_HIT + (stats.skill * 2 + stats.luck / 2) as i32,
+            avoid: (stats.speed * 2 + stats.luck) as i32,
+            crit: (stats.skill / 2) as i32,
+            crit_avoid: stats.luck as i32,
+            speed: stats.speed,
+        }
+    }
+
+    ///Fight with `weapon`. Weight over the wielder's constitution slows them down.
+    pub fn with_weapon(mut self, weapon: &WeaponData, constitution: u32) -> CombatStats
+    {
+        self.attack += weapon.might;
+        self.hit += weapon.hit - BASE_HIT;
+        self.crit += weapon.crit;
+        self.speed = self.speed.saturating_sub(weapon.weight.saturating_sub(constitution));
+        self
+    }
+
+    ///Add the defense and avoid bonuses of the tile the unit stands on.
+    pub fn with_terrain(mut self, tile: &Tile) -> CombatStats
+    {
+        self.defense += tile.defense;
+        self.avoid += tile.avoid;
+        self
+    }
+
+    ///Add the hit bonus for standing higher than the foe. Standing lower has no penalty.
+    pub fn with_height(mut self, own: u32, foe: u32) -> CombatStats
+    {
+        self.hit += HEIGHT_HIT_BONUS * own.saturating_sub(foe) as i32;
+        self
+    }
+}
+
+/// A single swing in a fight.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Strike
+{
+    ///True if the unit that started the fight made this strike, false for counterattacks.
+    pub by_attacker: bool,
+    pub hit: bool,
+    pub crit: bool,
+    pub damage: u32,
+}
+
+/// Everything that happened in one fight, in order.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct CombatResult
+{
+    pub strikes: Vec<Strike>,
+    pub attacker_dead: bool,
+    pub defender_dead: bool,
+}
+
+///Percent chance for `attacker` to hit `defender`, clamped to 0..=100.
+pub fn hit_chance(attacker: &CombatStats, defender: &CombatStats) -> u32
+{
+    (attacker.hit - defender.avoid).clamp(0, 100) as u32
+}
+
+///Percent chance for a hit from `attacker` to be a critical, clamped to 0..=100.
+pub fn crit_chance(attacker: &CombatStats, defender: &CombatStats) -> u32
+{
+    (attacker.crit - defender.crit_avoid).clamp(0, 100) as u32
+}
+
+///Damage of a single non critical hit.
+pub fn damage(attacker: &CombatStats, defender: &CombatStats) -> u32
+{
+    attacker.attack.saturating_sub(defender.defense)
+}
+
+///Roll one strike and apply its damage.
+fn strike
+(
+    by_attacker: bool,
+    striker: &CombatStats,
+    target: &CombatStats,
+    target_hp: &mut Health,
+    rng: &mut impl Roll
+) -> Strike
+{
+    let hit = rng.roll_hit(hit_chance(striker, target));
+    let crit = hit && rng.roll() < crit_chance(striker, target);
+    let mut dealt = 0;
+    if hit
+    {
+        let multiplier = if crit {CRIT_MULTIPLIER} else {1};
+        dealt = target_hp.take_damage(damage(striker, target) * multiplier);
+    }
+    Strike{by_attacker, hit, crit, damage: dealt}
+}
+
+///Resolve a whole fight.
+///
+///The attacker strikes first, then the defender counters if `can_counter` is set. Whoever is at least
+///`DOUBLE_ATTACK_SPEED` faster strikes a second time. The fight stops as soon as either side dies.
+///
+///`on_hit` is told which side landed each hit. It returns false once that side's weapon broke, and that side doesn't strike again.
+pub fn resolve_combat
+(
+    attacker: &CombatStats,
+    attacker_hp: &mut Health,
+    defender: &CombatStats,
+    defender_hp: &mut Health,
+    can_counter: bool,
+    rng: &mut impl Roll,
+    mut on_hit: impl FnMut(bool) -> bool
+) -> CombatResult
+{
+    let mut order = vec![true];
+    if can_counter
+    {
+        order.push(false);
+    }
+    if attacker.speed >= defender.speed + DOUBLE_ATTACK_SPEED
+    {
+        order.push(true);
+    } else if can_counter && defender.speed >= attacker.speed + DOUBLE_ATTACK_SPEED
+    {
+        order.push(false);
+    }
+
+    let mut result = CombatResult::default();
+    let (mut attacker_armed, mut defender_armed) = (true, true);
+    for by_attacker in order
+    {
+        if attacker_hp.is_dead() || defender_hp.is_dead()
+        {
+            break;
+        }
+        let armed = if by_attacker {&mut attacker_armed} else {&mut defender_armed};
+        if !*armed
+        {
+            continue;
+        }
+        let swing = if by_attacker
+        {
+            strike(true, attacker, defender, defender_hp, rng)
+        } else
+        {
+            strike(false, defender, attacker, attacker_hp, rng)
+        };
+        if swing.hit
+        {
+            *armed = on_hit(by_attacker);
+        }
+        result.strikes.push(swing);
+    }
+    result.attacker_dead = attacker_hp.is_dead();
+    result.defender_dead = defender_hp.is_dead();
+    result
+}
+
+///Spend a use of the weapon that just landed a hit. Returns false if that broke it.
+fn wear_weapon(items: &mut Inventory, name: &str) -> bool
+{
+    match items.use_equipped()
+    {
+        Some(broken) =>
+        {
+            println!("{}'s {} broke!", name, broken);
+            false
+        },
+        None => true,
+    }
+}
+
+///Manhattan distance between two tiles.
+pub fn distance(a: Location, b: Location) -> usize
+{
+    a.0.abs_diff(b.0) + a.1.abs_diff(b.1)
+}
+
+#[derive(Event)]
+pub struct AttackUnit
+{
+    pub attacker: Entity,
+    pub defender: Entity,
+}
+
+#[derive(Event)]
+pub struct CombatFinished
+{
+    pub attacker: Entity,
+    pub defender: Entity,
+    pub result: CombatResult,
+}
+
+///Run every requested fight, apply the damage, wear down weapons, and despawn whoever died.
+pub fn resolve_attacks
+(
+    mut cmd: Commands,
+    mut attacks: EventReader<AttackUnit>,
+    mut unit_qry: Query<(&Stats, &mut Health, &Location, &ObjName, &mut Inventory), With<IsUnit>>,
+    team_qry: Query<(&Team, &Experience, Has<Lord>)>,
+    map_qry: Query<(&TileMap, &TileList, &HeightMap)>,
+    mut sel_qry: Query<&mut SelectedUnit>,
+    mut fallen: ResMut<Fallen>,
+    mut rng: ResMut<GameRng>,
+    mut combat_finished: EventWriter<CombatFinished>,
+    mut gain_exp: EventWriter<GainExp>
+)
+{
+    for attack in attacks.read()
+    {
+        let Ok([(a_stats, mut a_hp, a_loc, a_name, mut a_items), (d_stats, mut d_hp, d_loc, d_name, mut d_items)]) = unit_qry.get_many_mut([attack.attacker, attack.defender]) else
+        {
+            println!("One of the units in this fight doesn't exist anymore.");
+            continue;
+        };
+        let dist = distance(*a_loc, *d_loc);
+        let (Some(a_weapon), true) = (a_items.equipped(), a_items.in_range(dist)) else
+        {
+            println!("{} has no weapon that reaches {}.", a_name.0, d_name.0);
+            combat_finished.send(CombatFinished{attacker: attack.attacker, defender: attack.defender, result: CombatResult::default()});
+            continue;
+        };
+        let can_counter = d_items.in_range(dist);
+        let combat_stats = |stats: &Stats, weapon: Option<&Item>, loc: &Location, foe: &Location|
+        {
+            let mut combat = CombatStats::from_stats(stats);
+            if let Some(item) = weapon
+            {
+                combat = combat.with_weapon(&item.weapon, stats.constitution);
+            }
+            match map_qry.get_single()
+            {
+                Ok((tile_map, tile_list, heights)) => combat
+                    .with_terrain(&tile_list.0[&tile_map.0[loc.1][loc.0]])
+                    .with_height(heights.at(*loc), heights.at(*foe)),
+                Err(_) => combat,
+            }
+        };
+        let a_combat = combat_stats(a_stats, Some(a_weapon), a_loc, d_loc);
+        let d_combat = combat_stats(d_stats, d_items.equipped(), d_loc, a_loc);
+        let result = resolve_combat(&a_combat, &mut a_hp, &d_combat, &mut d_hp, can_counter, &mut *rng, |by_attacker|
+        {
+            if by_attacker {wear_weapon(&mut a_items, &a_name.0)} else {wear_weapon(&mut d_items, &d_name.0)}
+        });
+        println!("{} attacks {}: {:?}", a_name.0, d_name.0, result.strikes);
+
+        //Only the player's units grow, and only if they lived through the fight.
+        for (by_attacker, unit, foe, dead, foe_dead) in [(true, attack.attacker, attack.defender, result.attacker_dead, result.defender_dead), (false, attack.defender, attack.attacker, result.defender_dead, result.attacker_dead)]
+        {
+            let (Ok((team, own, _)), Ok((_, foe, _))) = (team_qry.get(unit), team_qry.get(foe)) else {continue};
+            if team.0 == PLAYER_TEAM && !dead
+            {
+                let hit = result.strikes.iter().any(|strike| strike.by_attacker == by_attacker && strike.hit);
+                gain_exp.send(GainExp{unit, amount: combat_exp(own.level, foe.level, hit, foe_dead)});
+            }
+        }
+
+        for (dead, unit, name) in [(result.attacker_dead, attack.attacker, &a_name.0), (result.defender_dead, attack.defender, &d_name.0)]
+        {
+            if dead
+            {
+                if let Ok((team, _, lord)) = team_qry.get(unit)
+                {
+                    fallen.0.push(FallenUnit{name: name.clone(), team: team.0, lord});
+                }
+                cmd.entity(unit).despawn_recursive();
+                if let Ok(mut selected) = sel_qry.get_single_mut()
+                {
+                    if selected.selected_unit == Some(unit)
+                    {
+                        *selected = SelectedUnit::default();
+                    }
+                }
+            }
+        }
+        combat_finished.send(CombatFinished{attacker: attack.attacker, defender: attack.defender, result});
+    }
+}
+
+#[cfg(test)]
+mod test
+{
+    use super::*;
+    use rand::rngs::mock::StepRng;
+
+    fn health(current: u32, temp: u32) -> Health
+    {
+        Health{max: 20, current, temp}
+    }
+
+    #[test]
+    pub fn test_take_damage()
+    {
+        let mut hp = health(10, 3);
+        assert_eq!(hp.take_damage(5), 5);
+        assert_eq!(hp, health(8, 0));
+        assert_eq!(hp.take_damage(20), 8);
+        assert!(hp.is_dead());
+    }
+
+    #[test]
+    pub fn test_counter_and_double()
+    {
+        //StepRng(0, 0) always rolls 0, so every strike with a non zero chance hits.
+        let mut rng = StepRng::new(0, 0);
+        let attacker = CombatStats{speed: 9, crit: 0, ..default()};
+        let defender = CombatStats{crit: 0, ..default()};
+        let (mut a_hp, mut d_hp) = (health(20, 0), health(20, 0));
+        let result = resolve_combat(&attacker, &mut a_hp, &defender, &mut d_hp, true, &mut rng, |_| true);
+        assert_eq!(result.strikes.iter().map(|s| s.by_attacker).collect::<Vec<_>>(), vec![true, false, true]);
+        assert_eq!(d_hp.current, 12);
+        assert_eq!(a_hp.current, 16);
+        assert!(!result.defender_dead);
+
+        let (mut a_hp, mut d_hp) = (health(20, 0), health(20, 0));
+        let result = resolve_combat(&attacker, &mut a_hp, &defender, &mut d_hp, false, &mut rng, |_| true);
+        assert_eq!(result.strikes.len(), 2);
+        assert_eq!(a_hp.current, 20);
+    }
+
+    #[test]
+    pub fn test_death_stops_combat()
+    {
+        let mut rng = StepRng::new(0, 0);
+        let attacker = CombatStats{crit: 0, ..default()};
+        let defender = CombatStats{crit: 0, speed: 9, ..default()};
+        let (mut a_hp, mut d_hp) = (health(4, 0), health(20, 0));
+        let result = resolve_combat(&attacker, &mut a_hp, &defender, &mut d_hp, true, &mut rng, |_| true);
+        assert_eq!(result.strikes.len(), 2);
+        assert!(result.attacker_dead);
+        assert!(!result.defender_dead);
+    }
+
+    #[test]
+    pub fn test_miss_and_crit()
+    {
+        let mut rng = GameRng::new(7);
+        let attacker = CombatStats{hit: 0, ..default()};
+        let (mut a_hp, mut d_hp) = (health(20, 0), health(20, 0));
+        let result = resolve_combat(&attacker, &mut a_hp, &CombatStats::default(), &mut d_hp, false, &mut rng, |_| true);
+        assert!(!result.strikes[0].hit);
+        assert_eq!(d_hp.current, 20);
+
+        let mut rng = StepRng::new(0, 0);
+        let attacker = CombatStats{crit: 100, ..default()};
+        let (mut a_hp, mut d_hp) = (health(20, 0), health(20, 0));
+        let result = resolve_combat(&attacker, &mut a_hp, &CombatStats::default(), &mut d_hp, false, &mut rng, |_| true);
+        assert!(result.strikes[0].crit);
+        assert_eq!(result.strikes[0].damage, 12);
+    }
+
+    #[test]
+    pub fn test_seeded_combat_repeats()
+    {
+        let fight = |seed|
+        {
+            let mut rng = GameRng::new(seed);
+            let (mut a_hp, mut d_hp) = (health(20, 0), health(20, 0));
+            let attacker = CombatStats{speed: 9, crit: 20, ..default()};
+            let result = resolve_combat(&attacker, &mut a_hp, &CombatStats::default(), &mut d_hp, true, &mut rng, |_| true);
+            (result, rng.state())
+        };
+        assert_eq!(fight(11), fight(11));
+    }
+
+    #[test]
+    pub fn test_terrain_bonus()
+    {
+        use crate::map_data::TileData;
+        let fort = Tile::from(&TileData{id: 4, name: "Fort".into(), mv_cost: 2.0, color: (0.0, 0.0, 0.0), defense: 2, avoid: 20, heal: 10});
+        let stats = CombatStats{defense: 3, avoid: 10, ..default()}.with_terrain(&fort);
+        assert_eq!((stats.defense, stats.avoid), (5, 30));
+        assert_eq!(CombatStats{hit: 80, ..default()}.with_height(3, 1).hit, 100);
+        assert_eq!(CombatStats{hit: 80, ..default()}.with_height(0, 2).hit, 80);
+    }
+
+    #[test]
+    pub fn test_weapon_breaks_mid_fight()
+    {
+        let mut rng = StepRng::new(0, 0);
+        let weapon = |name: &str, uses| WeaponData{name: name.into(), weapon_type: WeaponType::Sword, might: 5, hit: 90, crit: 0, weight: 0, min_range: 1, max_range: 1, uses};
+        let mut items = Inventory::default();
+        items.add(Item::new(&weapon("Rusty Sword", 1))).unwrap();
+        items.add(Item::new(&weapon("Iron Sword", 46))).unwrap();
+        let attacker = CombatStats{speed: 9, crit: 0, ..default()};
+        let (mut a_hp, mut d_hp) = (health(20, 0), health(20, 0));
+        let result = resolve_combat(&attacker, &mut a_hp, &CombatStats{crit: 0, ..default()}, &mut d_hp, false, &mut rng, |by_attacker|
+        {
+            !by_attacker || wear_weapon(&mut items, "Martin")
+        });
+        //The sword broke on the first hit, so there's no follow up strike and the spare isn't worn down.
+        assert_eq!(result.strikes.len(), 1);
+        assert_eq!(d_hp.current, 16);
+        assert_eq!(items.items.len(), 1);
+        assert_eq!(items.equipped().map(|item| item.uses), Some(46));
+    }
+
+    #[test]
+    pub fn test_weapon_stats()
+    {
+        let axe = WeaponData{name: "Iron Axe".into(), weapon_type: WeaponType::Axe, might: 8, hit: 75, crit: 5, weight: 10, min_range: 1, max_range: 1, uses: 45};
+        let stats = CombatStats{attack: 6, hit: BASE_HIT + 10, crit: 2, speed: 7, ..default()}.with_weapon(&axe, 7);
+        assert_eq!((stats.attack, stats.hit, stats.crit, stats.speed), (14, 85, 7, 4));
+        assert_eq!(CombatStats{speed: 2, ..default()}.with_weapon(&axe, 5).speed, 0);
+    }
+}
@@ -5,9 +5,11 @@ use bevy::{
 };
 
 use crate::map::*;
+use crate::unit::*;
 use crate::camera::*;
 use crate::shared::*;
-use bevy::math::bounding::*;
+use crate::save::*;
+use crate::replay::*;
 
 #[derive(Event)]
 pub struct MoveDirection(pub Vec3);
@@ -30,6 +32,10 @@ pub struct MoveSelectorLocation(pub Vec3);
 #[derive(Event)]
 pub struct Cancel;
 
+///The player wants to end their phase.
+#[derive(Event)]
+pub struct EndPhase;
+
 pub fn mouse_movement
 (
     mut cursor_moved: EventReader<CursorMoved>,
@@ -44,10 +50,9 @@ pub fn mouse_movement
 }
 
 pub fn mouse_pos_raycast(
-    res: Res<Assets<Mesh>>,
     w_qry: Query<&Window, With<PrimaryWindow>>,
     camera_qry: Query<(&GlobalTransform, &Camera), With<PrimaryCamera>>,
-    transform_qry: Query<(&Handle<Mesh>, &Transform), With<IsTile>>,
+    map_qry: Query<(&MapSize, &HeightMap)>,
     mut update_selector_location: EventWriter<UpdateSelectorLocation>,
     mut cursor_moved: EventReader<MouseToCursor>
 )
@@ -55,33 +60,25 @@ pub fn mouse_pos_raycast(
     //TODO Split Logic into correct modules - Eventually, the cursor should just appear on hover, and all this should do is trigger a select event
     for _event in cursor_moved.read()
     {
-        let mut tile_center = Vec3::ZERO;
-
-        let mouse_ray;
         let (c_trans, camera) = camera_qry.get_single().unwrap();
+        let Ok((map_size, heights)) = map_qry.get_single() else {return};
         let Some(m_pos) = w_qry.single().cursor_position() else {return};
         //let m_pos = event.position;
         let Some(m_ray) = camera.viewport_to_world(c_trans, m_pos) else {return};
-        mouse_ray = RayCast3d::from_ray(m_ray, 40.0);
-        let mut closest = 100.0;
-        
 
-        for (handle, h_trans) in &transform_qry
+        let (arrow_vec, tile_center, tile) = match pick_tile(m_ray, map_size, heights)
         {
-            let v2 = res.get(handle).unwrap().compute_aabb().unwrap();
-            let var = Aabb3d::new(h_trans.translation, v2.half_extents);
-            
-            if let Some(distance) = mouse_ray.aabb_intersection_at(&var)
+            Some((loc, hit)) => (hit, Vec3::new(loc.0 as f32, tile_surface(heights.at(loc)) - 0.1, loc.1 as f32), Some(loc)),
+            //Off the map the selector follows the ground plane instead.
+            None =>
             {
-                if distance < closest
-                {
-                    closest = distance;
-                    tile_center = h_trans.translation;
-                }
-            } else {}
-        }
-        let arrow_vec = m_ray.get_point(closest);
-        update_selector_location.send(UpdateSelectorLocation(SelectorLocation{precise_location: arrow_vec, tile_location: tile_center}));
+                let ground = tile_surface(0);
+                let distance = m_ray.intersect_plane(Vec3::Y * ground, InfinitePlane3d::new(Vec3::Y)).unwrap_or(100.0);
+                let point = m_ray.get_point(distance);
+                (point, Vec3::new(point.x.round(), ground - 0.1, point.z.round()), Location::from_world(point, map_size))
+            },
+        };
+        update_selector_location.send(UpdateSelectorLocation(SelectorLocation{precise_location: arrow_vec, tile_location: tile_center, tile}));
         //println!("update_selector_location fired");
     }    
 }
@@ -90,10 +87,13 @@ pub fn fire_select
 (
     mut select: EventWriter<Select>,
     mut cancel: EventWriter<Cancel>,
-    keys: Res<ButtonInput<MouseButton>>
+    keys: Res<ButtonInput<MouseButton>>,
+    ui_qry: Query<&Interaction>
 )
 {
-    if keys.just_pressed(MouseButton::Left)
+    //Clicks on menus shouldn't also select the tile underneath them.
+    let over_ui = ui_qry.iter().any(|interaction| *interaction != Interaction::None);
+    if keys.just_pressed(MouseButton::Left) && !over_ui
     {
         select.send(Select);
     }
@@ -160,3 +160,61 @@ pub fn get_rotation(
         mouse_to_cursor.send(MouseToCursor);
     }
 }
+
+///F1-F3 save to a slot, or load it with shift held. F5 suspends the battle and F9 resumes it.
+pub fn fire_save_load
+(
+    mut save_game: EventWriter<SaveGame>,
+    mut load_game: EventWriter<LoadGame>,
+    keys: Res<ButtonInput<KeyCode>>
+)
+{
+    let shift = keys.pressed(KeyCode::ShiftLeft) || keys.pressed(KeyCode::ShiftRight);
+    for (key, slot) in [(KeyCode::F1, 1), (KeyCode::F2, 2), (KeyCode::F3, 3)]
+    {
+        if keys.just_pressed(key)
+        {
+            if shift
+            {
+                load_game.send(LoadGame(SaveSlot::Slot(slot)));
+            } else
+            {
+                save_game.send(SaveGame(SaveSlot::Slot(slot)));
+            }
+        }
+    }
+    if keys.just_pressed(KeyCode::F5)
+    {
+        save_game.send(SaveGame(SaveSlot::Suspend));
+    }
+    if keys.just_pressed(KeyCode::F9)
+    {
+        load_game.send(LoadGame(SaveSlot::Suspend));
+    }
+}
+
+///F10 restarts the battle and plays back everything that has happened so far.
+pub fn fire_replay
+(
+    mut start_replay: EventWriter<StartReplay>,
+    keys: Res<ButtonInput<KeyCode>>
+)
+{
+    if keys.just_pressed(KeyCode::F10)
+    {
+        start_replay.send(StartReplay);
+    }
+}
+
+///Backspace takes back the last action this phase.
+pub fn fire_rewind
+(
+    mut rewind_turn: EventWriter<RewindTurn>,
+    keys: Res<ButtonInput<KeyCode>>
+)
+{
+    if keys.just_pressed(KeyCode::Backspace)
+    {
+        rewind_turn.send(RewindTurn);
+    }
+}
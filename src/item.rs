@@ -0,0 +1,295 @@
+use std::fmt;
+
+use bevy::{
+    asset::{io::Reader, AssetLoader, AsyncReadExt, LoadContext},
+    prelude::*,
+    reflect::TypePath,
+};
+use serde::{Deserialize, Serialize};
+
+///How many items a unit can carry.
+pub const INVENTORY_SIZE: usize = 5;
+
+/// The kind of weapon, as written in `items.ron`. It's only descriptive for now and doesn't change combat.
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WeaponType
+{
+    Sword,
+    Lance,
+    Axe,
+    Bow,
+}
+
+/// A weapon definition from `items.ron`.
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+pub struct WeaponData
+{
+    pub name: String,
+    pub weapon_type: WeaponType,
+    pub might: u32,
+    pub hit: i32,
+    pub crit: i32,
+    ///Speed is lowered by however much this is over the wielder's constitution.
+    pub weight: u32,
+    pub min_range: usize,
+    pub max_range: usize,
+    ///How many hits the weapon lasts for when new.
+    pub uses: u32,
+}
+
+/// Every item the game knows about.
+#[derive(Asset, TypePath, Deserialize, Debug)]
+pub struct ItemList(pub Vec<WeaponData>);
+
+impl ItemList
+{
+    pub fn get(&self, name: &str) -> Option<&WeaponData>
+    {
+        self.0.iter().find(|item| item.name == name)
+    }
+}
+
+/// A weapon a unit carries, along with how many uses it has left.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Item
+{
+    pub weapon: WeaponData,
+    pub uses: u32,
+}
+
+impl Item
+{
+    ///A weapon with all of its uses left.
+    pub fn new(weapon: &WeaponData) -> Item
+    {
+        Item{weapon: weapon.clone(), uses: weapon.uses}
+    }
+}
+
+/// The items a unit is carrying. The equipped item is the one used in combat.
+#[derive(Component, Clone, Debug, Default, PartialEq)]
+pub struct Inventory
+{
+    pub items: Vec<Item>,
+    pub equipped: Option<usize>,
+}
+
+impl Inventory
+{
+    ///Add an item if there is room for it. The first weapon picked up is equipped.
+    pub fn add(&mut self, item: Item) -> Result<(), Item>
+    {
+        if self.items.len() >= INVENTORY_SIZE
+        {
+            return Err(item);
+        }
+        self.items.push(item);
+        if self.equipped.is_none()
+        {
+            self.equipped = Some(self.items.len() - 1);
+        }
+        Ok(())
+    }
+
+    pub fn equipped(&self) -> Option<&Item>
+    {
+        self.items.get(self.equipped?)
+    }
+
+    ///Equip the item in slot `index`. Does nothing if the slot is empty.
+    pub fn equip(&mut self, index: usize) -> bool
+    {
+        if index >= self.items.len()
+        {
+            return false;
+        }
+        self.equipped = Some(index);
+        true
+    }
+
+    ///Min and max range of the equipped weapon. A unit with nothing equipped can't attack.
+    pub fn attack_range(&self) -> Option<(usize, usize)>
+    {
+        self.equipped().map(|item| (item.weapon.min_range, item.weapon.max_range))
+    }
+
+    ///Whether the equipped weapon reaches `dist` tiles away.
+    pub fn in_range(&self, dist: usize) -> bool
+    {
+        self.attack_range().is_some_and(|(min, max)| (min..=max).contains(&dist))
+    }
+
+    ///Spend one use of the equipped weapon. If that breaks it, the weapon is thrown away, the first item left is equipped,
+    ///and the broken weapon's name is returned.
+    pub fn use_equipped(&mut self) -> Option<String>
+    {
+        let index = self.equipped?;
+        let item = &mut self.items[index];
+        item.uses = item.uses.saturating_sub(1);
+        if item.uses > 0
+        {
+            return None;
+        }
+        let broken = self.items.remove(index);
+        self.equipped = if self.items.is_empty() {None} else {Some(0)};
+        Some(broken.weapon.name)
+    }
+}
+
+#[derive(Debug)]
+pub enum ItemLoadError
+{
+    Io(std::io::Error),
+    Ron(ron::error::SpannedError),
+    DuplicateItem(String),
+    BadRange(String),
+}
+
+impl fmt::Display for ItemLoadError
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result
+    {
+        match self
+        {
+            ItemLoadError::Io(err) => write!(f, "could not read item file: {}", err),
+            ItemLoadError::Ron(err) => write!(f, "could not parse item file: {}", err),
+            ItemLoadError::DuplicateItem(name) => write!(f, "item {} is defined more than once", name),
+            ItemLoadError::BadRange(name) => write!(f, "item {} has a min range above its max range", name),
+        }
+    }
+}
+
+impl std::error::Error for ItemLoadError {}
+
+impl From<std::io::Error> for ItemLoadError
+{
+    fn from(err: std::io::Error) -> Self
+    {
+        ItemLoadError::Io(err)
+    }
+}
+
+impl From<ron::error::SpannedError> for ItemLoadError
+{
+    fn from(err: ron::error::SpannedError) -> Self
+    {
+        ItemLoadError::Ron(err)
+    }
+}
+
+impl ItemList
+{
+    pub fn validate(&self) -> Result<(), ItemLoadError>
+    {
+        for (i, item) in self.0.iter().enumerate()
+        {
+            if self.0[..i].iter().any(|other| other.name == item.name)
+            {
+                return Err(ItemLoadError::DuplicateItem(item.name.clone()));
+            }
+            if item.min_range > item.max_range
+            {
+                return Err(ItemLoadError::BadRange(item.name.clone()));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Default)]
+pub struct ItemListLoader;
+
+impl AssetLoader for ItemListLoader
+{
+    type Asset = ItemList;
+    type Settings = ();
+    type Error = ItemLoadError;
+
+    async fn load<'a>
+    (
+        &'a self,
+        reader: &'a mut Reader<'_>,
+        _settings: &'a (),
+        _load_context: &'a mut LoadContext<'_>,
+    ) -> Result<ItemList, ItemLoadError>
+    {
+        let mut bytes = Vec::new();
+        reader.read_to_end(&mut bytes).await?;
+        let items = ron::de::from_bytes::<ItemList>(&bytes)?;
+        items.validate()?;
+        Ok(items)
+    }
+
+    fn extensions(&self) -> &[&str]
+    {
+        &["items.ron"]
+    }
+}
+
+///Handle to the item list used by the current battle.
+#[derive(Resource)]
+pub struct ItemAsset
+{
+    pub items: Handle<ItemList>,
+}
+
+///Start loading the item list. Needs to finish before any units are spawned.
+pub fn init_item_asset
+(
+    mut cmd: Commands,
+    asset_server: Res<AssetServer>
+)
+{
+    cmd.insert_resource(ItemAsset{items: asset_server.load("items/base.items.ron")});
+}
+
+#[cfg(test)]
+mod test
+{
+    use super::*;
+
+    fn sword(uses: u32) -> WeaponData
+    {
+        WeaponData{name: "Iron Sword".into(), weapon_type: WeaponType::Sword, might: 5, hit: 90, crit: 0, weight: 5, min_range: 1, max_range: 1, uses}
+    }
+
+    #[test]
+    pub fn test_inventory_break_and_equip()
+    {
+        let bow = WeaponData{name: "Iron Bow".into(), weapon_type: WeaponType::Bow, min_range: 2, max_range: 2, ..sword(30)};
+        let mut inventory = Inventory::default();
+        assert_eq!(inventory.attack_range(), None);
+        inventory.add(Item::new(&sword(2))).unwrap();
+        inventory.add(Item::new(&bow)).unwrap();
+        assert_eq!(inventory.attack_range(), Some((1, 1)));
+        assert!(inventory.in_range(1) && !inventory.in_range(2));
+
+        assert_eq!(inventory.use_equipped(), None);
+        assert_eq!(inventory.use_equipped(), Some("Iron Sword".into()));
+        assert_eq!(inventory.items.len(), 1);
+        assert_eq!(inventory.attack_range(), Some((2, 2)));
+        assert!(!inventory.equip(3));
+
+        for _ in 0..INVENTORY_SIZE
+        {
+            let _ = inventory.add(Item::new(&sword(10)));
+        }
+        assert_eq!(inventory.items.len(), INVENTORY_SIZE);
+    }
+
+    #[test]
+    pub fn test_validate_item_list()
+    {
+        let lance = WeaponData{name: "Javelin".into(), weapon_type: WeaponType::Lance, min_range: 1, max_range: 2, ..sword(20)};
+        assert!(ItemList(vec![sword(46), lance.clone()]).validate().is_ok());
+        assert!(matches!(
+            ItemList(vec![sword(46), lance, sword(20)]).validate(),
+            Err(ItemLoadError::DuplicateItem(name)) if name == "Iron Sword"
+        ));
+        let backwards = WeaponData{name: "Longbow".into(), min_range: 3, max_range: 2, ..sword(20)};
+        assert!(matches!(
+            ItemList(vec![sword(46), backwards]).validate(),
+            Err(ItemLoadError::BadRange(name)) if name == "Longbow"
+        ));
+    }
+}
@@ -0,0 +1,291 @@
+use std::time::Duration;
+
+use bevy::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::class::*;
+use crate::rng::*;
+use crate::shared::*;
+use crate::unit::*;
+
+///EXP needed to gain a level.
+pub const EXP_PER_LEVEL: u32 = 100;
+
+///Units stop gaining EXP once they reach this level.
+pub const MAX_LEVEL: u32 = 20;
+
+///Extra EXP for finishing off a foe, on top of the EXP for the fight.
+pub const KILL_EXP_BONUS: u32 = 20;
+
+///How long the level-up popup stays on screen.
+const POPUP_SECS: u64 = 3;
+
+/// A unit's level and the EXP it has towards the next one.
+#[derive(Component, Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub struct Experience
+{
+    pub level: u32,
+    pub exp: u32,
+}
+
+impl Default for Experience
+{
+    fn default() -> Self
+    {
+        Experience{level: 1, exp: 0}
+    }
+}
+
+impl Experience
+{
+    ///Add EXP and return how many levels were gained. EXP is thrown away at the max level.
+    pub fn add(&mut self, amount: u32) -> u32
+    {
+        let start = self.level;
+        self.exp += amount;
+        while self.exp >= EXP_PER_LEVEL && self.level < MAX_LEVEL
+        {
+            self.exp -= EXP_PER_LEVEL;
+            self.level += 1;
+        }
+        if self.level >= MAX_LEVEL
+        {
+            self.exp = 0;
+        }
+        self.level - start
+    }
+}
+
+/// Growth rates of the unit itself, added to its class's growths.
+#[derive(Component, Clone, Copy, Debug, Default, PartialEq)]
+pub struct PersonalGrowths(pub Growths);
+
+///EXP for one fight. Fighting stronger foes is worth more and weaker foes less.
+///
+///A unit that never landed a hit only gets 1 EXP.
+pub fn combat_exp(own_level: u32, foe_level: u32, hit: bool, killed: bool) -> u32
+{
+    if !hit
+    {
+        return 1;
+    }
+    let diff = foe_level as i32 - own_level as i32;
+    let mut exp = ((31 + diff) / 3).max(1);
+    if killed
+    {
+        exp += (KILL_EXP_BONUS as i32 + diff * 3).max(0);
+    }
+    exp.clamp(1, EXP_PER_LEVEL as i32) as u32
+}
+
+/// The stats gained from a single level-up.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct LevelUp
+{
+    pub hp: u32,
+    pub stats: Stats,
+}
+
+///Roll each stat against its growth rate. Health and stats already at the class's cap don't go up.
+pub fn roll_level_up
+(
+    stats: &Stats,
+    max_hp: u32,
+    growths: &Growths,
+    caps: &Caps,
+    rng: &mut impl Roll
+) -> LevelUp
+{
+    let mut grows = |growth: u32, current: u32, cap: u32| (rng.roll() < growth && current < cap) as u32;
+    let hp = grows(growths.hp, max_hp, caps.hp);
+    let (values, rates, caps) = (stats.values(), growths.stats.values(), caps.stats.values());
+    let gains = std::array::from_fn(|i| grows(rates[i], values[i], caps[i]));
+    LevelUp{hp, stats: Stats::from_values(gains)}
+}
+
+///Sent when a unit earns EXP.
+#[derive(Event)]
+pub struct GainExp
+{
+    pub unit: Entity,
+    pub amount: u32,
+}
+
+#[derive(Component)]
+pub struct LevelUpPopup(Timer);
+
+///Give out EXP, roll any level-ups and show what went up. Only the latest level-up keeps its popup.
+pub fn gain_exp
+(
+    mut cmd: Commands,
+    mut gain_exp: EventReader<GainExp>,
+    mut unit_qry: Query<(&ObjName, &Class, &PersonalGrowths, &mut Experience, &mut Stats, &mut Health)>,
+    popup_qry: Query<Entity, With<LevelUpPopup>>,
+    mut rng: ResMut<GameRng>
+)
+{
+    //Popups spawned this run aren't in popup_qry until the commands are applied, so they're tracked here too.
+    let mut shown = popup_qry.iter().collect::<Vec<_>>();
+    for event in gain_exp.read()
+    {
+        let Ok((name, class, personal, mut experience, mut stats, mut health)) = unit_qry.get_mut(event.unit) else {continue};
+        let levels = experience.add(event.amount);
+        println!("{} gains {} EXP.", name.0, event.amount);
+        if levels == 0
+        {
+            continue;
+        }
+        let growths = class.0.growths.plus(&personal.0);
+        let mut total = LevelUp::default();
+        for _ in 0..levels
+        {
+            let gained = roll_level_up(&stats, health.max, &growths, &class.0.caps, &mut *rng);
+            *stats = stats.plus(&gained.stats);
+            health.max += gained.hp;
+            health.current += gained.hp;
+            total = LevelUp{hp: total.hp + gained.hp, stats: total.stats.plus(&gained.stats)};
+        }
+
+        for popup in shown.drain(..)
+        {
+            cmd.entity(popup).despawn_recursive();
+        }
+        shown.push(spawn_level_up_popup(&mut cmd, &name.0, experience.level, &total));
+    }
+}
+
+fn spawn_level_up_popup(cmd: &mut Commands, name: &str, level: u32, gained: &LevelUp) -> Entity
+{
+    let mut lines = vec![format!("{} reached level {}!", name, level)];
+    if gained.hp > 0
+    {
+        lines.push(format!("HP +{}", gained.hp));
+    }
+    for (stat, gain) in Stats::NAMES.iter().zip(gained.stats.values())
+    {
+        if gain > 0
+        {
+            lines.push(format!("{} +{}", stat, gain));
+        }
+    }
+
+    cmd.spawn((NodeBundle
+    {
+        style: Style
+        {
+            position_type: PositionType::Absolute,
+            left: Val::Percent(40.0),
+            top: Val::Px(40.0),
+            flex_direction: FlexDirection::Column,
+            row_gap: Val::Px(4.0),
+            padding: UiRect::all(Val::Px(10.0)),
+            ..default()
+        },
+        background_color: BackgroundColor(Color::srgba(0.0, 0.0, 0.0, 0.7)),
+        ..default()
+    },
+    LevelUpPopup(Timer::new(Duration::from_secs(POPUP_SECS), TimerMode::Once)),
+    )).with_children(|root|
+    {
+        for (i, line) in lines.into_iter().enumerate()
+        {
+            let color = if i == 0 {Color::srgb(1.0, 0.85, 0.3)} else {Color::WHITE};
+            root.spawn(TextBundle::from_section(line, TextStyle{font_size: 24.0, color, ..default()}));
+        }
+    }).id()
+}
+
+pub fn close_level_up_popup
+(
+    mut cmd: Commands,
+    time: Res<Time>,
+    mut popup_qry: Query<(Entity, &mut LevelUpPopup)>
+)
+{
+    for (popup, mut timer) in popup_qry.iter_mut()
+    {
+        if timer.0.tick(time.delta()).finished()
+        {
+            cmd.entity(popup).despawn_recursive();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test
+{
+    use super::*;
+    use rand::rngs::mock::StepRng;
+
+    #[test]
+    pub fn test_combat_exp()
+    {
+        assert_eq!(combat_exp(5, 5, false, false), 1);
+        assert_eq!(combat_exp(5, 5, true, false), 10);
+        assert_eq!(combat_exp(5, 8, true, false), 11);
+        assert_eq!(combat_exp(5, 5, true, true), 30);
+        assert_eq!(combat_exp(15, 1, true, true), 5);
+        assert_eq!(combat_exp(1, 20, true, true), 93);
+    }
+
+    #[test]
+    pub fn test_experience_add()
+    {
+        let mut experience = Experience{level: 1, exp: 90};
+        assert_eq!(experience.add(30), 1);
+        assert_eq!(experience, Experience{level: 2, exp: 20});
+        let mut experience = Experience{level: MAX_LEVEL - 1, exp: 50};
+        assert_eq!(experience.add(250), 1);
+        assert_eq!(experience, Experience{level: MAX_LEVEL, exp: 0});
+    }
+
+    #[test]
+    pub fn test_roll_level_up_caps()
+    {
+        //StepRng(0, 0) always rolls 0, so every non zero growth succeeds.
+        let mut rng = StepRng::new(0, 0);
+        let stats = Stats{strength: 10, skill: 20, ..default()};
+        let growths = Growths{hp: 50, stats: Stats{strength: 40, skill: 40, speed: 10, ..default()}};
+        let caps = Caps{hp: 40, stats: Stats::from_values([20; 8])};
+        let gained = roll_level_up(&stats, 40, &growths, &caps, &mut rng);
+        assert_eq!(gained.hp, 0);
+        assert_eq!(roll_level_up(&stats, 39, &growths, &caps, &mut rng).hp, 1);
+        assert_eq!(gained.stats, Stats{strength: 1, speed: 1, ..default()});
+    }
+
+    #[test]
+    pub fn test_one_popup_per_frame()
+    {
+        let mut app = App::new();
+        app.add_event::<GainExp>()
+            .insert_resource(GameRng::new(3))
+            .add_systems(Update, gain_exp);
+        let class = ClassData
+        {
+            name: "Lord".into(),
+            base: Stats::default(),
+            movement: 5.0,
+            movement_type: MovementType::Foot,
+            terrain_costs: default(),
+            animation_set: "Placeholder".into(),
+            growths: Growths::default(),
+            caps: Caps::default(),
+        };
+        //Two player units level up in the same frame.
+        for name in ["Martin", "Alan"]
+        {
+            let unit = app.world_mut().spawn((
+                ObjName(name.into()),
+                Class(class.clone()),
+                PersonalGrowths::default(),
+                Experience{level: 1, exp: 95},
+                Stats::default(),
+                Health::default(),
+            )).id();
+            app.world_mut().send_event(GainExp{unit, amount: 10});
+        }
+        app.update();
+        let popups = app.world_mut().query::<&LevelUpPopup>().iter(app.world()).count();
+        assert_eq!(popups, 1);
+    }
+}
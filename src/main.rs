@@ -1,22 +1,50 @@
-//use serde::*;
+use serde::{Deserialize, Serialize};
 use bevy::{
     ecs::{schedule::SystemSetConfig, system::EntityCommands}, input::InputPlugin, prelude::*, sprite::*, transform::TransformSystem, utils::HashMap, window::{CursorGrabMode, PresentMode, PrimaryWindow, WindowLevel, WindowMode, WindowTheme},
-    asset::LoadState
+    asset::{LoadState, RecursiveDependencyLoadState, UntypedAssetId}
 };
 //use bevy_flycam::prelude::*;
 //use bevy_editor_pls::controls::EditorControls;
 
+mod ai;
+mod animation;
 mod map;
+mod map_data;
+mod menu;
+mod objective;
+mod pathing;
+mod replay;
+mod rng;
+mod save;
 mod shared;
+mod turn;
 mod camera;
+mod class;
+mod combat;
 mod input;
+mod item;
+mod level;
 mod unit;
 
 
+use ai::*;
+use animation::*;
 use map::*;
+use map_data::*;
+use menu::*;
+use objective::*;
+use pathing::*;
+use replay::*;
+use rng::*;
+use save::*;
 use shared::*;
+use turn::*;
 use camera::*;
+use class::*;
+use combat::*;
 use input::*;
+use item::*;
+use level::*;
 use unit::*;
 
 //use bevy_editor_pls::EditorPlugin;
@@ -27,10 +55,12 @@ use bevy_sprite3d::*;
 #[derive(SystemSet, States, Debug, Clone, PartialEq, Eq, Hash, Default)]
 enum GameState
 {
-    #[default] BattleMap
+    #[default] BattleMap,
+    ///The battle is over and its result is on screen.
+    Result,
 }
 
-#[derive(SystemSet, SubStates, Debug, Clone, PartialEq, Eq, Hash, Default)]
+#[derive(SystemSet, SubStates, Debug, Clone, PartialEq, Eq, Hash, Default, Serialize, Deserialize)]
 #[source(GameState = GameState::BattleMap)]
 enum Phase
 {
@@ -93,6 +123,14 @@ fn main() -> Result<(), String> {
             //EditorPlugin::default(),
         ))
         .add_plugins(Sprite3dPlugin)
+        .init_asset::<MapData>()
+        .init_asset_loader::<MapDataLoader>()
+        .init_asset::<ClassList>()
+        .init_asset_loader::<ClassListLoader>()
+        .init_asset::<ItemList>()
+        .init_asset_loader::<ItemListLoader>()
+        .init_asset::<AnimationSetList>()
+        .init_asset_loader::<AnimationSetLoader>()
         .init_state::<LoadingState>()
         .init_state::<GameState>()
             .add_sub_state::<Phase>()
@@ -122,11 +160,21 @@ fn main() -> Result<(), String> {
         )
 
         //Perform initial loading of sprite textures because Sprite3d needs the textures to be preloaded
+        //The map data is loaded alongside them so the map can be built from it.
         .add_systems
         (OnEnter(GameState::BattleMap), 
             (
-            //unit
-                init_unit_sprite
+            //animation
+                init_animation_asset
+                    .run_if(in_state(LoadingState::LoadingSpriteTextures)),
+            //map
+                init_map_asset
+                    .run_if(in_state(LoadingState::LoadingSpriteTextures)),
+            //class
+                init_class_asset
+                    .run_if(in_state(LoadingState::LoadingSpriteTextures)),
+            //item
+                init_item_asset
                     .run_if(in_state(LoadingState::LoadingSpriteTextures)),
             )
         )
@@ -150,12 +198,17 @@ fn main() -> Result<(), String> {
                 default_camera,
             //map
                 init_map,
-                load_map
-                    .after(init_map),
                 populate_grid
-                    .after(load_map),
+                    .after(init_map),
+                init_overlay_assets,
+                init_terrain_panel,
             //unit
                 init_unit_model,
+            //rng
+                init_game_rng,
+            //replay
+                init_command_log
+                    .after(init_game_rng),
             //main
                 setup_ambient_light
             )
@@ -166,41 +219,140 @@ fn main() -> Result<(), String> {
         (Update, 
             (
             //main
-                player_phase_state_handler,
+                player_phase_state_handler
+                    .run_if(in_state(Phase::Player))
+                    .run_if(not(resource_exists::<Replay>)),
+                advance_phase
+                    .after(check_player_phase_done)
+                    .after(run_ai_turn)
+                    .after(apply_replay)
+                    .run_if(in_state(GameState::BattleMap)),
+            //turn
+                (
+                    mark_player_acted
+                        .run_if(in_state(Phase::Player)),
+                    check_player_phase_done
+                        .after(mark_player_acted)
+                        .run_if(in_state(Player::Field))
+                        .run_if(not(resource_exists::<Replay>)),
+                    grey_out_acted,
+                    restore_acted_color,
+                ),
+            //ai
+                run_ai_turn
+                    .run_if(in_state(Phase::AI))
+                    .run_if(not(resource_exists::<Replay>)),
+            //combat
+                resolve_attacks,
+            //replay
+                (
+                    record_moves
+                        .run_if(not(resource_exists::<Replay>)),
+                    record_attacks
+                        .before(resolve_attacks)
+                        .run_if(not(resource_exists::<Replay>)),
+                    start_replay,
+                    rewind_turn
+                        .before(start_replay),
+                    apply_replay
+                        .before(resolve_attacks)
+                        .run_if(resource_exists::<Replay>),
+                ),
+            //level
+                (
+                    gain_exp
+                        .after(resolve_attacks),
+                    close_level_up_popup,
+                ),
+            //objective
+                check_objectives
+                    .after(resolve_attacks)
+                    .run_if(in_state(GameState::BattleMap))
+                    .run_if(in_state(LoadingState::MainLoop)),
             //unit
-                test_move_one_right,
-                update_render_location, 
-                synch_unit_map,
-                face_camera,
-                animate_sprites,
+                (
+                    test_move_one_right,
+                    update_render_location, 
+                    walk_units,
+                    synch_unit_map,
+                    face_camera,
+                    animate_sprites,
+                    tint_team_colors
+                        .before(grey_out_acted),
+                ),
             //input
-                mouse_movement
-                    .before(mouse_pos_raycast),
-                mouse_pos_raycast,
-                get_move_direction
-                    .before(move_camera)
-                    .before(mouse_pos_raycast), 
-                get_rotation
-                    .before(move_camera)
-                    .before(mouse_pos_raycast), 
-                fire_select
-                    .after(mouse_pos_raycast),
+                (
+                    mouse_movement
+                        .before(mouse_pos_raycast),
+                    mouse_pos_raycast,
+                    get_move_direction
+                        .before(move_camera)
+                        .before(mouse_pos_raycast), 
+                    get_rotation
+                        .before(move_camera)
+                        .before(mouse_pos_raycast), 
+                    fire_select
+                        .after(mouse_pos_raycast),
+                ),
             //map
-                render_grid, 
-                update_selector_location
-                    .before(tile_select),
-                tile_select,
-                render_selector
-                    .after(update_selector_location),
+                (
+                    render_grid, 
+                    update_selector_location
+                        .before(tile_select),
+                    tile_select,
+                    render_selector
+                        .after(update_selector_location),
+                    update_terrain_panel
+                        .after(update_selector_location),
+                ),
                 //Field System Set
                 (
                     FIELD_unit_selected,
                 )
                     .in_set(Player::Field),
                 (
+                    update_movement_range
+                        .before(movement),
+                    update_range_overlay
+                        .after(update_movement_range),
+                    render_path_preview
+                        .after(update_movement_range),
                     movement
                 )
                     .in_set(Player::Movement),
+                //Menu System Set
+                (
+                    navigate_menu,
+                    highlight_menu
+                        .after(navigate_menu),
+                    handle_menu_choice
+                        .after(navigate_menu),
+                    undo_move
+                        .after(player_phase_state_handler),
+                    (despawn_menu, spawn_menu)
+                        .chain()
+                        .after(handle_menu_choice)
+                        .after(player_phase_state_handler)
+                        .run_if(on_event::<RefreshMenu>()),
+                )
+                    .in_set(Player::ActionMenu),
+                (
+                    render_attack_targets,
+                    select_attack_target,
+                )
+                    .in_set(Player::Action),
+                //Saving and loading only happens between actions so nothing is half done, and never while a replay is running.
+                (
+                    fire_save_load,
+                    save_game
+                        .after(fire_save_load),
+                    load_game
+                        .after(fire_save_load),
+                    fire_replay,
+                    fire_rewind,
+                )
+                    .in_set(Player::Field)
+                    .run_if(not(resource_exists::<Replay>)),
                 debug_selected_unit,
             //camera
                 move_camera
@@ -213,9 +365,65 @@ fn main() -> Result<(), String> {
         (OnEnter(Player::Movement), 
             (
                 entry_unit_selected,
+                mark_rewind_point,
             )
         )
         
+        .add_systems
+        (OnExit(Player::Movement), 
+            (
+                clear_movement_range,
+                clear_range_overlay,
+            )
+        )
+
+        .add_systems
+        (OnEnter(Player::ActionMenu), 
+            (
+                spawn_menu,
+            )
+        )
+
+        .add_systems
+        (OnExit(Player::ActionMenu), 
+            (
+                despawn_menu,
+            )
+        )
+
+        .add_systems
+        (OnEnter(Phase::AI),
+            (
+                start_ai_phase,
+                reset_acted,
+                terrain_heal,
+            )
+        )
+
+        .add_systems
+        (OnExit(Phase::AI),
+            (
+                advance_turn,
+            )
+        )
+
+        .add_systems
+        (OnEnter(GameState::Result),
+            (
+                spawn_result_screen,
+            )
+        )
+
+        .add_systems
+        (OnEnter(Phase::Player),
+            (
+                reset_acted,
+                terrain_heal,
+                clear_rewind_points
+                    .run_if(not(resource_exists::<Replay>)),
+            )
+        )
+
         .add_systems
         (OnEnter(Player::Field),
             (
@@ -224,14 +432,32 @@ fn main() -> Result<(), String> {
             )
         )
 
+        .init_resource::<MovementRange>()
+        .init_resource::<AiTurn>()
+        .init_resource::<Turn>()
+        .init_resource::<ActiveMenu>()
+        .init_resource::<RewindPoints>()
+        .init_resource::<Fallen>()
         .add_event::<MoveDirection>()
         .add_event::<Rotate>()
         .add_event::<Select>()
         .add_event::<Cancel>()
+        .add_event::<EndPhase>()
         .add_event::<UpdateSelectorLocation>()
         .add_event::<UpdateUnitRenderLocation>()
         .add_event::<MouseToCursor>()
         .add_event::<UnitOnTile>()
+        .add_event::<AttackUnit>()
+        .add_event::<CombatFinished>()
+        .add_event::<GainExp>()
+        .add_event::<StartReplay>()
+        .add_event::<RewindTurn>()
+        .add_event::<UndoMove>()
+        .add_event::<WalkFinished>()
+        .add_event::<MenuChoice>()
+        .add_event::<RefreshMenu>()
+        .add_event::<SaveGame>()
+        .add_event::<LoadGame>()
         //.insert_resource(editor_controls())
         .run();
 
@@ -260,54 +486,132 @@ fn editor_controls() -> EditorControls {
 */
 
 
-///Check if images in "ImageAsset" are finished loading, and if so, switch the state to the main loop.
+///Check if the sprite sheets in "AnimationAsset" and the data in "MapAsset", "ClassAsset" and "ItemAsset" are finished loading, and if so, switch the state to the main loop.
 fn done_load_sprite
 (
     asset_server: Res<AssetServer>,
-    assets: Res<ImageAsset>,
+    animation_asset: Res<AnimationAsset>,
+    map_asset: Res<MapAsset>,
+    class_asset: Res<ClassAsset>,
+    item_asset: Res<ItemAsset>,
     mut next_state: ResMut<NextState<LoadingState>>
 )
 {
-    if asset_server.get_load_state(assets.image.id()) == Some(LoadState::Loaded)
+    let ids: [UntypedAssetId; 4] = [animation_asset.sets.id().untyped(), map_asset.map.id().untyped(), class_asset.classes.id().untyped(), item_asset.items.id().untyped()];
+    for id in ids
+    {
+        if let Some(LoadState::Failed(err)) = asset_server.get_load_state(id)
+        {
+            panic!("Couldn't load battle data: {}", err);
+        }
+        if asset_server.get_recursive_dependency_load_state(id) == Some(RecursiveDependencyLoadState::Failed)
+        {
+            panic!("Couldn't load a sprite sheet needed by the battle data!");
+        }
+    }
+    if ids.iter().all(|id| asset_server.is_loaded_with_dependencies(*id))
     {
         next_state.set(LoadingState::MainLoop)
     }
 }
 
+///Hand the turn over to the other side when a phase ends, and log it unless a replay is running.
+fn advance_phase
+(
+    mut end_phase: EventReader<EndPhase>,
+    phase: Res<State<Phase>>,
+    mut next_phase: ResMut<NextState<Phase>>,
+    mut log: ResMut<CommandLog>,
+    replay: Option<Res<Replay>>
+)
+{
+    //Several systems can decide the phase is over in the same frame, but it only ends once.
+    if end_phase.read().count() == 0
+    {
+        return;
+    }
+    if replay.is_none()
+    {
+        log.push(BattleCommand::EndTurn);
+    }
+    next_phase.set(match phase.get()
+    {
+        Phase::Player => Phase::AI,
+        Phase::AI => Phase::Player,
+    });
+}
+
 fn player_phase_state_handler
 (
     current_state: Res<State<Player>>,
     mut next_state: ResMut<NextState<Player>>,
     mut update_sel_unit: EventReader<UnitOnTile>,
-    mut cancel: EventReader<Cancel>
+    mut cancel: EventReader<Cancel>,
+    mut combat_finished: EventReader<CombatFinished>,
+    mut walk_finished: EventReader<WalkFinished>,
+    mut menu: ResMut<ActiveMenu>,
+    mut refresh_menu: EventWriter<RefreshMenu>,
+    mut undo_move: EventWriter<UndoMove>,
+    unit_qry: Query<(&Team, Has<Acted>)>
 )
 {
     for event in update_sel_unit.read()
     {
-        if event.0.is_some()
+        if *current_state.get() != Player::Field
         {
-            println!("OH GOD MY STATE IS CHANGING AAAAAAA");
-            match current_state.get()
+            continue;
+        }
+        if let Some(unit) = event.0
+        {
+            if !unit_qry.get(unit).is_ok_and(|(team, acted)| can_select(team, acted))
             {
-                Player::Field => next_state.set(Player::Movement),
-                //Player::Movement => println!("Already in movement"),
-                _ => println!("Already in farthest possible state or somehow fucked up and ended up in uncreated state")
+                println!("That unit can't be moved right now.");
+                continue;
             }
-        } else if *current_state.get() == Player::Field
+            println!("OH GOD MY STATE IS CHANGING AAAAAAA");
+            next_state.set(Player::Movement);
+        } else
         {
-            println!("Need to change to menu state here")
+            menu.kind = MenuKind::Field;
+            next_state.set(Player::ActionMenu);
+        }
+    }
+    for _event in combat_finished.read()
+    {
+        next_state.set(Player::Field);
+    }
+    for _event in walk_finished.read()
+    {
+        if *current_state.get() == Player::Movement
+        {
+            menu.kind = MenuKind::Action;
+            next_state.set(Player::ActionMenu);
         }
     }
     //todo Maybe save last state and have cancel just revert to previous state to simplify this a bit. May need to get reworked entirely too as cancel shouldn't always change the state.
     for _event in cancel.read()
     {
         println!("MY STATE IS CHANGING BAAAACK AAAA");
-        match current_state.get()
+        match (current_state.get(), menu.kind)
         {
-            Player::Movement => next_state.set(Player::Field),
+            (Player::Movement, _) => next_state.set(Player::Field),
+            (Player::ActionMenu, MenuKind::Field) => next_state.set(Player::Field),
+            (Player::ActionMenu, MenuKind::Action) =>
+            {
+                undo_move.send(UndoMove);
+            },
+            (Player::ActionMenu, MenuKind::Items) =>
+            {
+                menu.kind = MenuKind::Action;
+                refresh_menu.send(RefreshMenu);
+            },
+            (Player::ActionMenu, MenuKind::Options) =>
+            {
+                menu.kind = MenuKind::Field;
+                refresh_menu.send(RefreshMenu);
+            },
+            (Player::Action, _) => next_state.set(Player::ActionMenu),
             _ => println!("Already in most basic state or fucked up and in uncreated state")
         }
     }
 }
-
-
@@ -1,37 +1,256 @@
 use bevy::{
-    color::palettes::{css::{BLACK, BLUE, GHOST_WHITE, GREEN, RED}, tailwind::{GREEN_400, GREEN_950}}, ecs::{schedule::SystemSetConfig, system::EntityCommands}, prelude::*, sprite::*, transform::TransformSystem, utils::HashMap, window::{CursorGrabMode, PresentMode, PrimaryWindow, WindowLevel, WindowMode, WindowTheme}
+    color::palettes::css::{BLUE, RED, YELLOW}, ecs::{schedule::SystemSetConfig, system::EntityCommands}, prelude::*, sprite::*, transform::TransformSystem, utils::HashMap, window::{CursorGrabMode, PresentMode, PrimaryWindow, WindowLevel, WindowMode, WindowTheme}
 };
 
 use std::f32::consts::PI;
 
 use crate::{shared::*, Player, Select, UnitID, UpdateSelectorLocation};
 use crate::unit::*;
+use crate::animation::*;
+use crate::map_data::*;
+use crate::pathing::*;
+use crate::combat::*;
+use crate::turn::*;
+use crate::objective::*;
+use crate::replay::*;
 
 pub struct Tile
 {
-    name: String,
-    id: u32,
-    mv_cost: f32,
+    pub name: String,
+    pub id: u32,
+    pub mv_cost: f32,
+    pub defense: u32,
+    pub avoid: i32,
+    ///Percent of max health healed at the start of each turn.
+    pub heal: u32,
     rand_info: Color,
 }
 
+impl From<&TileData> for Tile
+{
+    fn from(data: &TileData) -> Self
+    {
+        let (r, g, b) = data.color;
+        Tile
+        {
+            name: data.name.clone(),
+            id: data.id,
+            mv_cost: data.mv_cost,
+            defense: data.defense,
+            avoid: data.avoid,
+            heal: data.heal,
+            rand_info: Color::srgb(r, g, b),
+        }
+    }
+}
+
 #[derive(Component)]
 pub struct MapSize(pub usize, pub usize);
 
 #[derive(Component)]
-pub struct TileMap(Vec<Vec<u32>>);
+pub struct TileMap(pub Vec<Vec<u32>>);
+
+///Height of the top of a tile at height level 0.
+const TILE_TOP: f32 = 0.6;
+
+///How far up a single height level raises a tile.
+pub const LEVEL_HEIGHT: f32 = 0.5;
+
+/// Elevation of every tile in height levels, indexed `[z][x]` like `TileMap`.
+#[derive(Component)]
+pub struct HeightMap(pub Vec<Vec<u32>>);
+
+impl HeightMap
+{
+    pub fn at(&self, loc: Location) -> u32
+    {
+        self.0.get(loc.1).and_then(|row| row.get(loc.0)).copied().unwrap_or(0)
+    }
+}
+
+///World y of the top surface of a tile at the given height level.
+pub fn tile_surface(height: u32) -> f32
+{
+    TILE_TOP + height as f32 * LEVEL_HEIGHT
+}
+
+///Find the first tile a ray hits, along with the point it hits.
+///
+///Each tile is a solid column reaching up to its surface. The ray is walked cell by cell over the grid (DDA),
+///so the cost depends on how many cells the ray crosses rather than how many tiles the map has.
+pub fn pick_tile(ray: Ray3d, map_size: &MapSize, heights: &HeightMap) -> Option<(Location, Vec3)>
+{
+    let (origin, dir) = (ray.origin, *ray.direction);
+    let (o, d) = (origin.xz(), dir.xz());
+    let bounds_min = Vec2::splat(-0.5);
+    let bounds_max = Vec2::new(map_size.0 as f32 - 0.5, map_size.1 as f32 - 0.5);
+
+    //Clip the ray to the part that is over the map.
+    let (mut t_start, mut t_end) = (0.0_f32, f32::INFINITY);
+    for axis in 0..2
+    {
+        if d[axis] == 0.0
+        {
+            if o[axis] < bounds_min[axis] || o[axis] > bounds_max[axis]
+            {
+                return None;
+            }
+            continue;
+        }
+        let (t1, t2) = ((bounds_min[axis] - o[axis]) / d[axis], (bounds_max[axis] - o[axis]) / d[axis]);
+        t_start = t_start.max(t1.min(t2));
+        t_end = t_end.min(t1.max(t2));
+    }
+    if t_start > t_end
+    {
+        return None;
+    }
+
+    let start = o + d * t_start;
+    //Rounding can put a ray that starts on the far edge one cell past it.
+    let mut cell = [0, 1].map(|axis| (start[axis].round() as isize).clamp(0, [map_size.0, map_size.1][axis] as isize - 1));
+    let step = [0, 1].map(|axis| if d[axis] > 0.0 {1} else {-1});
+    //Ray distance to the next cell edge on each axis, and between edges.
+    let mut t_next = [0, 1].map(|axis| if d[axis] == 0.0 {f32::INFINITY} else {(cell[axis] as f32 + 0.5 * step[axis] as f32 - o[axis]) / d[axis]});
+    let t_delta = [0, 1].map(|axis| if d[axis] == 0.0 {f32::INFINITY} else {1.0 / d[axis].abs()});
+
+    let mut t_enter = t_start;
+    while let Some(loc) = Location::from_signed(cell[0], cell[1], map_size)
+    {
+        let top = tile_surface(heights.at(loc));
+        let t_exit = t_next[0].min(t_next[1]).min(t_end);
+        //Already below the top when entering the cell means the ray hit the side of the column.
+        if origin.y + dir.y * t_enter <= top
+        {
+            return Some((loc, ray.get_point(t_enter)));
+        }
+        if origin.y + dir.y * t_exit <= top
+        {
+            return Some((loc, ray.get_point((top - origin.y) / dir.y)));
+        }
+        if t_exit >= t_end
+        {
+            return None;
+        }
+        let axis = if t_next[0] < t_next[1] {0} else {1};
+        cell[axis] += step[axis];
+        t_enter = t_next[axis];
+        t_next[axis] += t_delta[axis];
+    }
+    None
+}
+
+#[derive(Component)]
+pub struct TileList(pub HashMap<u32, Tile>);
 
+/// Which unit stands on which tile.
+///
+/// Keeps the invariant that a tile holds at most one unit and a unit stands on at most one tile.
 #[derive(Component)]
-pub struct TileList(HashMap<u32, Tile>);
+pub struct UnitMap
+{
+    cells: Vec<Vec<Option<Entity>>>,
+    positions: HashMap<Entity, Location>,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum OccupancyError
+{
+    OutOfBounds(Location),
+    Occupied(Entity),
+    AlreadyPlaced(Location),
+    NotPlaced,
+}
+
+impl UnitMap
+{
+    pub fn new(width: usize, height: usize) -> UnitMap
+    {
+        UnitMap
+        {
+            cells: vec![vec![None; width]; height],
+            positions: HashMap::new(),
+        }
+    }
 
-#[derive(Component, Deref, DerefMut)]
-pub struct UnitMap(Vec<Vec<Option<Entity>>>);
+    pub fn in_bounds(&self, loc: Location) -> bool
+    {
+        loc.1 < self.cells.len() && loc.0 < self.cells[loc.1].len()
+    }
+
+    ///The unit standing on a tile. Out of bounds tiles are always empty.
+    pub fn get(&self, loc: Location) -> Option<Entity>
+    {
+        self.cells.get(loc.1).and_then(|row| row.get(loc.0)).copied().flatten()
+    }
+
+    pub fn location_of(&self, unit: Entity) -> Option<Location>
+    {
+        self.positions.get(&unit).copied()
+    }
+
+    ///Put a unit that isn't on the map yet onto an empty tile.
+    pub fn place(&mut self, unit: Entity, loc: Location) -> Result<(), OccupancyError>
+    {
+        if let Some(current) = self.location_of(unit)
+        {
+            return Err(OccupancyError::AlreadyPlaced(current));
+        }
+        self.check_free(unit, loc)?;
+        self.cells[loc.1][loc.0] = Some(unit);
+        self.positions.insert(unit, loc);
+        Ok(())
+    }
+
+    ///Move a unit that is already on the map, returning the tile it left.
+    pub fn move_unit(&mut self, unit: Entity, to: Location) -> Result<Location, OccupancyError>
+    {
+        let Some(from) = self.location_of(unit) else {return Err(OccupancyError::NotPlaced)};
+        self.check_free(unit, to)?;
+        self.cells[from.1][from.0] = None;
+        self.cells[to.1][to.0] = Some(unit);
+        self.positions.insert(unit, to);
+        Ok(from)
+    }
+
+    ///Take a unit off the map, returning the tile it was on.
+    pub fn remove(&mut self, unit: Entity) -> Option<Location>
+    {
+        let loc = self.positions.remove(&unit)?;
+        self.cells[loc.1][loc.0] = None;
+        Some(loc)
+    }
+
+    pub fn clear(&mut self)
+    {
+        for row in self.cells.iter_mut()
+        {
+            row.fill(None);
+        }
+        self.positions.clear();
+    }
+
+    fn check_free(&self, unit: Entity, loc: Location) -> Result<(), OccupancyError>
+    {
+        if !self.in_bounds(loc)
+        {
+            return Err(OccupancyError::OutOfBounds(loc));
+        }
+        match self.get(loc)
+        {
+            Some(other) if other != unit => Err(OccupancyError::Occupied(other)),
+            _ => Ok(()),
+        }
+    }
+}
 
 #[derive(Component, Default, Clone, Copy)]
 pub struct SelectedUnit
 {
-    selected_unit: Option<Entity>,
-    selected_loc: Option<Location>,
+    pub selected_unit: Option<Entity>,
+    pub selected_loc: Option<Location>,
+    ///Where the selected unit stood before its last move, so the move can be undone.
+    pub origin: Option<Location>,
 }
 
 #[derive(Event)]
@@ -40,100 +259,72 @@ pub struct UnitOnTile(pub Option<Entity>, pub Option<Location>);
 #[derive(Component)]
 pub struct IsTile();
 
+///Marks the flat quads drawn over tiles to show movement and attack range.
+#[derive(Component)]
+pub struct RangeOverlay;
+
+///Mesh and materials shared by every range overlay quad.
+#[derive(Resource)]
+pub struct OverlayAssets
+{
+    mesh: Handle<Mesh>,
+    move_material: Handle<StandardMaterial>,
+    attack_material: Handle<StandardMaterial>,
+}
+
 #[derive(Bundle)]
 pub struct MapBundle
 {
     map_name: ObjName,
     map_size: MapSize,
     tile_map: TileMap,
+    height_map: HeightMap,
     unit_map: UnitMap,
     tile_list: TileList,
-    selected_unit: SelectedUnit
+    selected_unit: SelectedUnit,
+    objective: MapObjective,
 }
 
-pub fn init_map(mut cmd: Commands)
+///Build the map entity from the loaded map data.
+pub fn init_map
+(
+    mut cmd: Commands,
+    map_asset: Res<MapAsset>,
+    maps: Res<Assets<MapData>>
+)
 {
+    let Some(map) = maps.get(&map_asset.map) else {panic!("Map data missing after loading finished!")};
+    let (width, height) = map.size;
     cmd.spawn(MapBundle
     {
-        map_name: ObjName("test".into()),
-        map_size: MapSize(24, 17),
-        tile_map: TileMap(vec![vec![1; 24]; 17]),
-        unit_map: UnitMap(vec![vec![None; 24]; 17]),
-        tile_list: TileList(HashMap::new()),
+        map_name: ObjName(map.name.clone()),
+        map_size: MapSize(width, height),
+        tile_map: TileMap(map.grid.clone()),
+        height_map: HeightMap(if map.heights.is_empty() {vec![vec![0; width]; height]} else {map.heights.clone()}),
+        unit_map: UnitMap::new(width, height),
+        tile_list: TileList(map.tiles.iter().map(|tile| (tile.id, Tile::from(tile))).collect()),
         selected_unit: SelectedUnit
         {
             ..default()
-        }
+        },
+        objective: MapObjective(map.objective.clone()),
     });
     cmd.spawn(
     {
-        SelectorLocation{precise_location: Vec3::ZERO, tile_location: Vec3::ZERO}
-    });
-}
-
-pub fn load_map(mut qry: Query<(&MapSize, &mut TileMap, &mut TileList)>)
-{
-    let (map_size, mut tile_map, mut tile_list) = qry.single_mut();
-    tile_map.0 = 
-    vec![
-        vec![0,0,1,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0],
-        vec![0,0,1,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0],
-        vec![0,0,1,1,0,0,0,0,3,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0],
-        vec![0,0,1,1,0,0,0,0,3,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0],
-        vec![0,0,1,1,0,0,0,0,3,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0],
-        vec![0,0,1,1,0,0,0,0,3,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0],
-        vec![0,0,1,1,0,0,0,0,3,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0],
-        vec![0,0,1,0,0,0,0,0,3,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0],
-        vec![0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0],
-        vec![0,0,0,2,2,2,2,2,2,2,0,0,0,0,0,0,0,0,0,0,0,0,0,0],
-        vec![0,0,0,2,2,2,2,2,2,2,0,0,0,0,0,0,0,0,0,0,0,0,0,0],
-        vec![0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0],
-        vec![0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0],
-        vec![0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0],
-        vec![0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0],
-        vec![0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0],
-        vec![0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0],
-    ];
-    tile_list.0.insert(0, Tile
-    {
-        name: "Plain".into(),
-        id: 0,
-        mv_cost: 1.0,
-        rand_info: Color::Srgba(GREEN),
-    });
-    tile_list.0.insert(1, Tile
-    {
-        name: "Forest".into(),
-        id: 1,
-        mv_cost: 1.5,
-        rand_info: Color::Srgba(GREEN_400),
-    });
-    tile_list.0.insert(2, Tile
-    {
-        name: "Road".into(),
-        id: 2,
-        mv_cost: 0.8,
-        rand_info: Color::Srgba(GHOST_WHITE),
-    });
-    tile_list.0.insert(3, Tile
-    {
-        name: "Cliff".into(),
-        id: 3,
-        mv_cost: 99.0,
-        rand_info: Color::Srgba(BLACK),
+        SelectorLocation{precise_location: Vec3::ZERO, tile_location: Vec3::ZERO, tile: None}
     });
 }
 
-pub fn render_grid(mut giz: Gizmos)
+pub fn render_grid(mut giz: Gizmos, qry: Query<&MapSize>)
 {
-    
-    for z in 0..18
+    let Ok(map_size) = qry.get_single() else {return};
+    for z in 0..=map_size.1
     {
-        giz.ray(Vec3::new(-0.5, 0.61, -0.5 + z as f32), Vec3::new(24.0, 0.0, 0.0), Color::Srgba(RED))
+        giz.ray(Vec3::new(-0.5, 0.61, -0.5 + z as f32), Vec3::new(map_size.0 as f32, 0.0, 0.0), Color::Srgba(RED))
     }
-    for x in 0..25
+    for x in 0..=map_size.0
     {
-        giz.ray(Vec3::new(-0.5 + x as f32,0.61,-0.5), Vec3::new(0.0,0.0,17.0), Color::Srgba(RED))
+        giz.ray(Vec3::new(-0.5 + x as f32,0.61,-0.5), Vec3::new(0.0,0.0,map_size.1 as f32), Color::Srgba(RED))
     }
 }
 
@@ -142,17 +333,19 @@ pub fn populate_grid(
     asset_server: Res<AssetServer>,
     mut meshs: ResMut<Assets<Mesh>>, 
     mut materials: ResMut<Assets<StandardMaterial>>,
-    mut qry: Query<(&TileMap, &TileList)>)
+    mut qry: Query<(&TileMap, &TileList, &HeightMap)>)
 {
     let mut z = 0;
     
     let h = meshs.add(Cuboid::new(1.0, 0.2, 1.0));
-    let (col, tile_list) = qry.single();
+    let level = meshs.add(Cuboid::new(1.0, LEVEL_HEIGHT, 1.0));
+    let (col, tile_list, heights) = qry.single();
     for column in &col.0
     {
         let mut x = 0;
         for id in column
         {
+            let height = heights.at(Location(x, z));
             cmd.spawn((PbrBundle
             {
                 mesh: h.clone(),
@@ -161,11 +354,30 @@ pub fn populate_grid(
                         base_color: tile_list.0[id].rand_info,
                         ..default()
                     }),
-                transform: Transform::from_xyz(x as f32, 0.5, z as f32),
+                transform: Transform::from_xyz(x as f32, tile_surface(height) - 0.1, z as f32),
                 ..default()
             },
             IsTile(),
             ));
+            //Raised tiles sit on a stack of darker cuboids, one per height level.
+            if height > 0
+            {
+                let side = materials.add(StandardMaterial
+                {
+                    base_color: tile_list.0[id].rand_info.darker(0.2),
+                    ..default()
+                });
+                for i in 0..height
+                {
+                    cmd.spawn(PbrBundle
+                    {
+                        mesh: level.clone(),
+                        material: side.clone(),
+                        transform: Transform::from_xyz(x as f32, tile_surface(i) - 0.2 + LEVEL_HEIGHT / 2.0, z as f32),
+                        ..default()
+                    });
+                }
+            }
             x += 1;
         }
         z += 1;
@@ -183,6 +395,76 @@ pub fn populate_grid(
         });
 }
 
+pub fn init_overlay_assets
+(
+    mut cmd: Commands,
+    mut meshs: ResMut<Assets<Mesh>>,
+    mut materials: ResMut<Assets<StandardMaterial>>
+)
+{
+    let overlay_material = |color: Color| StandardMaterial
+    {
+        base_color: color,
+        alpha_mode: AlphaMode::Blend,
+        unlit: true,
+        ..default()
+    };
+    cmd.insert_resource(OverlayAssets
+    {
+        mesh: meshs.add(Plane3d::default().mesh().size(0.9, 0.9)),
+        move_material: materials.add(overlay_material(Color::srgba(0.2, 0.4, 1.0, 0.5))),
+        attack_material: materials.add(overlay_material(Color::srgba(1.0, 0.2, 0.2, 0.5))),
+    });
+}
+
+///Redraw the movement (blue) and attack (red) overlays whenever the movement range changes.
+pub fn update_range_overlay
+(
+    mut cmd: Commands,
+    range: Res<MovementRange>,
+    overlay_assets: Res<OverlayAssets>,
+    overlay_qry: Query<Entity, With<RangeOverlay>>,
+    height_qry: Query<&HeightMap>
+)
+{
+    if !range.is_changed()
+    {
+        return;
+    }
+    for overlay in &overlay_qry
+    {
+        cmd.entity(overlay).despawn();
+    }
+    let tiles = range.reachable.keys()
+        .map(|loc| (loc, overlay_assets.move_material.clone()))
+        .chain(range.attackable.iter().map(|loc| (loc, overlay_assets.attack_material.clone())));
+    let Ok(heights) = height_qry.get_single() else {return};
+    for (loc, material) in tiles
+    {
+        cmd.spawn((PbrBundle
+        {
+            mesh: overlay_assets.mesh.clone(),
+            material,
+            transform: Transform::from_xyz(loc.0 as f32, tile_surface(heights.at(*loc)) + 0.005, loc.1 as f32),
+            ..default()
+        },
+        RangeOverlay,
+        ));
+    }
+}
+
+pub fn clear_range_overlay
+(
+    mut cmd: Commands,
+    overlay_qry: Query<Entity, With<RangeOverlay>>
+)
+{
+    for overlay in &overlay_qry
+    {
+        cmd.entity(overlay).despawn();
+    }
+}
+
 pub fn update_selector_location(
     mut qry: Query<&mut SelectorLocation>,
     mut update_selector_loc: EventReader<UpdateSelectorLocation>
@@ -196,6 +478,57 @@ pub fn update_selector_location(
     }
 }
 
+/// The panel that shows the stats of the tile under the selector.
+#[derive(Component)]
+pub struct TerrainPanel;
+
+pub fn init_terrain_panel(mut cmd: Commands)
+{
+    cmd.spawn((TextBundle::from_section
+    (
+        "",
+        TextStyle
+        {
+            font_size: 20.0,
+            color: Color::WHITE,
+            ..default()
+        }
+    )
+    .with_style(Style
+    {
+        position_type: PositionType::Absolute,
+        left: Val::Px(20.0),
+        bottom: Val::Px(20.0),
+        padding: UiRect::all(Val::Px(6.0)),
+        ..default()
+    })
+    .with_background_color(Color::srgba(0.0, 0.0, 0.0, 0.6)),
+    TerrainPanel,
+    ));
+}
+
+///Show the name and bonuses of the hovered tile, or hide the panel when the selector is off the map.
+pub fn update_terrain_panel
+(
+    sel_qry: Query<&SelectorLocation, Changed<SelectorLocation>>,
+    map_qry: Query<(&TileMap, &TileList)>,
+    mut panel_qry: Query<(&mut Text, &mut Visibility), With<TerrainPanel>>
+)
+{
+    let Ok(selector_loc) = sel_qry.get_single() else {return};
+    let Ok((tile_map, tile_list)) = map_qry.get_single() else {return};
+    let Ok((mut text, mut visibility)) = panel_qry.get_single_mut() else {return};
+
+    let Some(loc) = selector_loc.tile else
+    {
+        *visibility = Visibility::Hidden;
+        return;
+    };
+    let tile = &tile_list.0[&tile_map.0[loc.1][loc.0]];
+    text.sections[0].value = format!("{}\nDef {}  Avo {}\nHeal {}%  Move {}", tile.name, tile.defense, tile.avoid, tile.heal, tile.mv_cost);
+    *visibility = Visibility::Inherited;
+}
+
 pub fn render_selector(
     mut gizmo: Gizmos,
     qry: Query<&SelectorLocation>
@@ -218,41 +551,31 @@ pub fn render_selector(
 
 pub fn tile_select
 (
-    map_qry: Query<(&TileMap, &TileList, &MapSize, &UnitMap)>,
+    map_qry: Query<(&TileMap, &TileList, &UnitMap)>,
     sel_qry: Query<&SelectorLocation>,
     mut sel_loc: EventReader<Select>,
     mut unit_on_tile: EventWriter<UnitOnTile>
 )
 {
-    let (tile_map, tile_list, map_size, unit_map) = map_qry.single();
+    let (tile_map, tile_list, unit_map) = map_qry.single();
     let selector_loc = sel_qry.single();
 
     for _event in sel_loc.read()
     {
-        let x = (selector_loc.precise_location.x + 0.5) as usize;
-        let z = (selector_loc.precise_location.z + 0.5) as usize;
-
-        if x >= map_size.0 || z >= map_size.1
-        {
-            break;
-        }
+        //Clicks off the map don't select anything.
+        let Some(loc) = selector_loc.tile else {break};
 
-        println!("{} {} {}", x, z, tile_list.0[&tile_map.0[z][x]].name);
+        println!("{} {} {}", loc.0, loc.1, tile_list.0[&tile_map.0[loc.1][loc.0]].name);
 
-        if let Some(unit) = unit_map[z][x]
-        {
-            unit_on_tile.send(UnitOnTile(Some(unit), Some(Location(x, z))));
-        } else 
-        {
-            unit_on_tile.send(UnitOnTile(None, Some(Location(x, z))));
-        }
+        unit_on_tile.send(UnitOnTile(unit_map.get(loc), Some(loc)));
     }
 }
 
 pub fn FIELD_unit_selected
 (
     mut update_selected_unit: EventReader<UnitOnTile>,
-    mut sel_qry: Query<&mut SelectedUnit>
+    mut sel_qry: Query<&mut SelectedUnit>,
+    unit_qry: Query<(&Team, Has<Acted>)>
 )
 {
     let mut selected_unit = sel_qry.single_mut();
@@ -260,6 +583,10 @@ pub fn FIELD_unit_selected
     {
         if let (Some(sel_unit), Some(sel_unit_loc)) = (event.0, event.1)
         {
+            if !unit_qry.get(sel_unit).is_ok_and(|(team, acted)| can_select(team, acted))
+            {
+                continue;
+            }
             selected_unit.selected_unit = Some(sel_unit);
             selected_unit.selected_loc = Some(sel_unit_loc);
         } else
@@ -271,40 +598,101 @@ pub fn FIELD_unit_selected
 
 pub fn movement
 (
+    mut cmd: Commands,
     mut sel_unit_qry: Query<&mut SelectedUnit>,
-    mut unit_map_qry: Query<&mut UnitMap>,
-    mut unit_qry: Query<(&mut Location, &Movement, Entity)>,
-    mut unit_on_tile: EventReader<UnitOnTile>
+    unit_qry: Query<(&AnimationLibrary, &Location, Has<WalkPath>)>,
+    mut unit_on_tile: EventReader<UnitOnTile>,
+    range: Res<MovementRange>
 )
 {
     //println!("movement");
-    if let Ok(mut unit_map) = unit_map_qry.get_single_mut()
+    for event in unit_on_tile.read()
     {
-        for event in unit_on_tile.read()
+        let mut selected_unit = sel_unit_qry.single_mut();
+        let Some(unit) = selected_unit.selected_unit else {continue};
+        let Ok((ani_lib, &loc, walking)) = unit_qry.get(unit) else {continue};
+        if walking
         {
-            if event.0.is_none()
-            {
-                let mut selected_unit = sel_unit_qry.single_mut();
-                if let Some(unit) = selected_unit.selected_unit
-                {
-                    if let Ok((mut loc, _movement, entity)) = unit_qry.get_mut(unit)
-                    {
-                        if let Some(new_loc) = event.1
-                        {
-                            unit_map[loc.1][loc.0] = None;
-                            *loc = new_loc;
-                            //unit_map[loc.1][loc.0] = Some(entity);
-                            selected_unit.selected_loc = Some(new_loc);
-                        }
-                    }
-                }
-            } else 
-            {
-                println!("Play negative noise. Can't stand here.")
-            }
+            continue;
+        }
+        let Some(new_loc) = event.1 else {continue};
+
+        //Clicking the unit itself means staying put, which goes straight to the action menu.
+        if event.0.is_some_and(|other| other != unit)
+        {
+            println!("Play negative noise. Can't stand here.");
+            continue;
         }
-    }   
 
+        let Some(path) = range.path_to(new_loc) else
+        {
+            println!("Play negative noise. Out of range.");
+            continue;
+        };
+        cmd.entity(unit).insert(WalkPath::new(path, ani_lib.current_animation()));
+        selected_unit.selected_loc = Some(new_loc);
+        selected_unit.origin = Some(loc);
+    }
+}
+
+///Sent to put the selected unit back where it was before it moved.
+#[derive(Event)]
+pub struct UndoMove;
+
+///Take back a move that hasn't been followed by an action yet and go back to picking where to move.
+pub fn undo_move
+(
+    mut undo_move: EventReader<UndoMove>,
+    mut sel_qry: Query<&mut SelectedUnit>,
+    mut unit_qry: Query<(&UnitID, &mut Location, &mut AnimationLibrary), Without<WalkPath>>,
+    mut log: ResMut<CommandLog>,
+    mut next_state: ResMut<NextState<Player>>
+)
+{
+    for _event in undo_move.read()
+    {
+        let mut selected = sel_qry.single_mut();
+        let (Some(unit), Some(origin)) = (selected.selected_unit, selected.origin) else {continue};
+        let Ok((id, mut loc, mut ani_lib)) = unit_qry.get_mut(unit) else {continue};
+        *loc = origin;
+        ani_lib.set_animation(ClipId::Idle);
+        log.remove_move(*id);
+        selected.selected_loc = Some(origin);
+        selected.origin = None;
+        next_state.set(Player::Movement);
+    }
+}
+
+///Draw an arrow along the path the selected unit would take to reach the hovered tile.
+pub fn render_path_preview
+(
+    mut gizmo: Gizmos,
+    range: Res<MovementRange>,
+    sel_qry: Query<&SelectorLocation>,
+    walking_qry: Query<(), With<WalkPath>>,
+    height_qry: Query<&HeightMap>
+)
+{
+    if !walking_qry.is_empty()
+    {
+        return;
+    }
+    let Some(hovered) = sel_qry.single().tile else {return};
+    let Some(path) = range.path_to(hovered) else {return};
+    let Ok(heights) = height_qry.get_single() else {return};
+    let points = path.iter()
+        .map(|loc| Vec3::new(loc.0 as f32, tile_surface(heights.at(*loc)) + 0.1, loc.1 as f32))
+        .collect::<Vec<_>>();
+    for (i, step) in points.windows(2).enumerate()
+    {
+        if i + 2 == points.len()
+        {
+            gizmo.arrow(step[0], step[1], Color::Srgba(YELLOW));
+        } else
+        {
+            gizmo.line(step[0], step[1], Color::Srgba(YELLOW));
+        }
+    }
 }
 
 pub fn entry_unit_selected
@@ -314,18 +702,19 @@ pub fn entry_unit_selected
     cur_state: Res<State<Player>>
 )
 {
+    //The selected unit can be cleared or despawned by combat, in which case there's nothing to animate.
     if let Some(sel_unit) = sel_unit_qry.single().selected_unit
     {
         if let Ok(mut unit_ani_lib) = unit_qry.get_mut(sel_unit)
         {
             match cur_state.get()
             {
-                Player::Movement => unit_ani_lib.set_animation("selected".into()),
-                Player::Field => unit_ani_lib.set_animation("idle".into()),
+                Player::Movement => unit_ani_lib.set_animation(ClipId::Selected),
+                Player::Field => unit_ani_lib.set_animation(ClipId::Idle),
                 _ => panic!("In map. Game in incomplete state.")
             }
-        } else {panic!("Literally how. entity has no animation library?")}
-    } else {panic!("Entity doesn't exsist. unit_selected_entry")}
+        }
+    }
 }
 
 pub fn debug_selected_unit
@@ -344,4 +733,120 @@ pub fn debug_selected_unit
             } else {panic!("Somehow no name?!?!")}
         } else {println!("Selected Unit has been cleared.")}
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod test
+{
+    use super::*;
+
+    fn ray(origin: Vec3, toward: Vec3) -> Ray3d
+    {
+        Ray3d::new(origin, toward - origin)
+    }
+
+    #[test]
+    pub fn test_pick_tile()
+    {
+        let size = MapSize(4, 3);
+        let flat = HeightMap(vec![vec![0; 4]; 3]);
+        let (loc, hit) = pick_tile(ray(Vec3::new(2.2, 10.0, 1.0), Vec3::new(2.2, 0.0, 1.0)), &size, &flat).unwrap();
+        assert_eq!(loc, Location(2, 1));
+        assert!((hit - Vec3::new(2.2, tile_surface(0), 1.0)).length() < 0.001);
+
+        //A slanted ray from outside the map still lands on the right tile.
+        let target = Vec3::new(0.3, tile_surface(0), 2.4);
+        let (loc, hit) = pick_tile(ray(Vec3::new(-6.0, 8.0, 2.4), target), &size, &flat).unwrap();
+        assert_eq!(loc, Location(0, 2));
+        assert!((hit - target).length() < 0.001);
+
+        //A tall column in the way is hit on its side.
+        let mut hill = HeightMap(vec![vec![0; 4]; 3]);
+        hill.0[1][1] = 4;
+        let (loc, hit) = pick_tile(ray(Vec3::new(-3.0, 2.0, 1.0), Vec3::new(3.0, tile_surface(0), 1.0)), &size, &hill).unwrap();
+        assert_eq!(loc, Location(1, 1));
+        assert!((hit.x - 0.5).abs() < 0.001);
+
+        //And on its top when looking straight down.
+        let (loc, hit) = pick_tile(ray(Vec3::new(1.0, 10.0, 1.0), Vec3::new(1.0, 0.0, 1.0)), &size, &hill).unwrap();
+        assert_eq!(loc, Location(1, 1));
+        assert!((hit.y - tile_surface(4)).abs() < 0.001);
+
+        assert_eq!(pick_tile(ray(Vec3::new(-2.0, 10.0, 1.0), Vec3::new(-2.0, 0.0, 1.0)), &size, &flat), None);
+        assert_eq!(pick_tile(ray(Vec3::new(1.0, 10.0, 1.0), Vec3::new(1.0, 20.0, 1.0)), &size, &flat), None);
+    }
+
+    #[test]
+    pub fn test_location_edges()
+    {
+        let size = MapSize(4, 3);
+        assert_eq!(Location::from_signed(0, 0, &size), Some(Location(0, 0)));
+        assert_eq!(Location::from_signed(3, 2, &size), Some(Location(3, 2)));
+        assert_eq!(Location::from_signed(-1, 0, &size), None);
+        assert_eq!(Location::from_signed(0, -1, &size), None);
+        assert_eq!(Location::from_signed(4, 0, &size), None);
+        assert_eq!(Location::from_signed(0, 3, &size), None);
+
+        //Just inside and just outside the left and top edges. These used to saturate to column 0.
+        assert_eq!(Location::from_world(Vec3::new(-0.45, 0.6, 0.0), &size), Some(Location(0, 0)));
+        assert_eq!(Location::from_world(Vec3::new(-0.55, 0.6, 1.0), &size), None);
+        assert_eq!(Location::from_world(Vec3::new(1.0, 0.6, -0.7), &size), None);
+        assert_eq!(Location::from_world(Vec3::new(-3.0, 0.6, -3.0), &size), None);
+        //And the right and bottom edges.
+        assert_eq!(Location::from_world(Vec3::new(3.45, 0.6, 2.45), &size), Some(Location(3, 2)));
+        assert_eq!(Location::from_world(Vec3::new(3.55, 0.6, 1.0), &size), None);
+        assert_eq!(Location::from_world(Vec3::new(1.0, 0.6, 2.55), &size), None);
+
+        //Rays that just miss the edge don't pick the edge tile.
+        let flat = HeightMap(vec![vec![0; 4]; 3]);
+        assert_eq!(pick_tile(ray(Vec3::new(-0.55, 10.0, 1.0), Vec3::new(-0.55, 0.0, 1.0)), &size, &flat), None);
+        assert_eq!(pick_tile(ray(Vec3::new(-0.45, 10.0, 1.0), Vec3::new(-0.45, 0.0, 1.0)), &size, &flat).map(|(loc, _)| loc), Some(Location(0, 1)));
+        assert_eq!(pick_tile(ray(Vec3::new(2.0, 10.0, 2.6), Vec3::new(2.0, 0.0, 2.6)), &size, &flat), None);
+    }
+
+    #[test]
+    pub fn test_unit_map_move()
+    {
+        let mut unit_map = UnitMap::new(4, 3);
+        let bob = Entity::from_raw(1);
+        let joe = Entity::from_raw(2);
+        assert_eq!(unit_map.place(bob, Location(0, 0)), Ok(()));
+        assert_eq!(unit_map.place(joe, Location(0, 0)), Err(OccupancyError::Occupied(bob)));
+        assert_eq!(unit_map.place(joe, Location(1, 0)), Ok(()));
+        assert_eq!(unit_map.place(bob, Location(2, 2)), Err(OccupancyError::AlreadyPlaced(Location(0, 0))));
+
+        assert_eq!(unit_map.move_unit(bob, Location(1, 0)), Err(OccupancyError::Occupied(joe)));
+        assert_eq!(unit_map.move_unit(bob, Location(3, 2)), Ok(Location(0, 0)));
+        assert_eq!(unit_map.get(Location(0, 0)), None);
+        assert_eq!(unit_map.get(Location(3, 2)), Some(bob));
+        assert_eq!(unit_map.location_of(bob), Some(Location(3, 2)));
+        //Moving onto the tile you're already on is fine.
+        assert_eq!(unit_map.move_unit(bob, Location(3, 2)), Ok(Location(3, 2)));
+        assert_eq!(unit_map.get(Location(3, 2)), Some(bob));
+    }
+
+    #[test]
+    pub fn test_unit_map_remove()
+    {
+        let mut unit_map = UnitMap::new(4, 3);
+        let bob = Entity::from_raw(1);
+        unit_map.place(bob, Location(2, 1)).unwrap();
+        assert_eq!(unit_map.remove(bob), Some(Location(2, 1)));
+        assert_eq!(unit_map.get(Location(2, 1)), None);
+        assert_eq!(unit_map.location_of(bob), None);
+        assert_eq!(unit_map.remove(bob), None);
+        assert_eq!(unit_map.move_unit(bob, Location(0, 0)), Err(OccupancyError::NotPlaced));
+    }
+
+    #[test]
+    pub fn test_unit_map_out_of_bounds()
+    {
+        let mut unit_map = UnitMap::new(4, 3);
+        let bob = Entity::from_raw(1);
+        assert_eq!(unit_map.place(bob, Location(4, 0)), Err(OccupancyError::OutOfBounds(Location(4, 0))));
+        assert_eq!(unit_map.place(bob, Location(0, 3)), Err(OccupancyError::OutOfBounds(Location(0, 3))));
+        assert_eq!(unit_map.get(Location(10, 10)), None);
+        unit_map.place(bob, Location(3, 2)).unwrap();
+        assert_eq!(unit_map.move_unit(bob, Location(3, 3)), Err(OccupancyError::OutOfBounds(Location(3, 3))));
+        assert_eq!(unit_map.get(Location(3, 2)), Some(bob));
+    }
+}
@@ -0,0 +1,359 @@
+use std::fmt;
+
+use bevy::{
+    asset::{io::Reader, AssetLoader, AsyncReadExt, LoadContext},
+    prelude::*,
+    reflect::TypePath,
+};
+use serde::Deserialize;
+
+use crate::class::{Growths, Stats};
+use crate::item::INVENTORY_SIZE;
+use crate::level::MAX_LEVEL;
+use crate::objective::*;
+use crate::unit::PLAYER_TEAM;
+
+/// A battle map as described by a `.map.ron` file.
+///
+/// `grid` is indexed as `grid[z][x]`, so it should contain `size.1` rows of `size.0` tile ids each.
+#[derive(Asset, TypePath, Deserialize, Debug)]
+pub struct MapData
+{
+    pub name: String,
+    pub size: (usize, usize),
+    pub tiles: Vec<TileData>,
+    pub grid: Vec<Vec<u32>>,
+    ///Elevation of each tile in height levels, indexed like `grid`. Left empty, the map is flat.
+    #[serde(default)]
+    pub heights: Vec<Vec<u32>>,
+    #[serde(default)]
+    pub units: Vec<UnitSpawn>,
+    #[serde(default)]
+    pub objective: Objective,
+}
+
+/// A single entry in the map's tile palette.
+#[derive(Deserialize, Debug, Clone)]
+pub struct TileData
+{
+    pub id: u32,
+    pub name: String,
+    pub mv_cost: f32,
+    ///Debug color of the tile in srgb.
+    pub color: (f32, f32, f32),
+    ///Added to the defense of a unit standing on the tile.
+    #[serde(default)]
+    pub defense: u32,
+    ///Added to the avoid of a unit standing on the tile. Can be negative.
+    #[serde(default)]
+    pub avoid: i32,
+    ///Percent of max health restored at the start of the owning team's phase.
+    #[serde(default)]
+    pub heal: u32,
+}
+
+/// A unit that starts the battle on the map.
+#[derive(Deserialize, Debug, Clone)]
+pub struct UnitSpawn
+{
+    pub name: String,
+    pub team: u32,
+    ///Name of the unit's class in `classes.ron`.
+    pub class: String,
+    ///Defaults to the class's base stats.
+    #[serde(default)]
+    pub stats: Option<Stats>,
+    ///Between 1 and `MAX_LEVEL`.
+    #[serde(default = "first_level")]
+    pub level: u32,
+    ///Added to the class's growths. Defaults to none.
+    #[serde(default)]
+    pub growths: Option<Growths>,
+    ///Animation set the unit is drawn with. Defaults to the class's set.
+    #[serde(default)]
+    pub sprite: Option<String>,
+    pub location: (usize, usize),
+    ///Names of the items in `items.ron` the unit starts with. The first weapon is equipped.
+    #[serde(default)]
+    pub items: Vec<String>,
+    ///The battle is lost if a player lord dies.
+    #[serde(default)]
+    pub lord: bool,
+    #[serde(default)]
+    pub boss: bool,
+}
+
+fn first_level() -> u32
+{
+    1
+}
+
+#[derive(Debug)]
+pub enum MapLoadError
+{
+    Io(std::io::Error),
+    Ron(ron::error::SpannedError),
+    EmptyMap,
+    RowCount { expected: usize, found: usize },
+    RaggedRow { row: usize, expected: usize, found: usize },
+    DuplicateTile(u32),
+    UnknownTile { x: usize, z: usize, id: u32 },
+    HeightRowCount { expected: usize, found: usize },
+    RaggedHeightRow { row: usize, expected: usize, found: usize },
+    SpawnOutOfBounds { x: usize, z: usize },
+    SpawnOccupied { x: usize, z: usize },
+    TooManyItems(String),
+    BadLevel { name: String, level: u32 },
+    ObjectiveOutOfBounds { x: usize, z: usize },
+    NoBoss,
+    MissingDefendUnit(String),
+}
+
+impl fmt::Display for MapLoadError
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result
+    {
+        match self
+        {
+            MapLoadError::Io(err) => write!(f, "could not read map file: {}", err),
+            MapLoadError::Ron(err) => write!(f, "could not parse map file: {}", err),
+            MapLoadError::EmptyMap => write!(f, "map size must be at least 1x1"),
+            MapLoadError::RowCount { expected, found } =>
+                write!(f, "map grid has {} rows but its size says {}", found, expected),
+            MapLoadError::RaggedRow { row, expected, found } =>
+                write!(f, "map grid row {} has {} tiles but its size says {}", row, found, expected),
+            MapLoadError::DuplicateTile(id) => write!(f, "tile id {} is defined more than once", id),
+            MapLoadError::UnknownTile { x, z, id } =>
+                write!(f, "tile ({}, {}) uses id {} which is not in the tile list", x, z, id),
+            MapLoadError::HeightRowCount { expected, found } =>
+                write!(f, "map heights have {} rows but its size says {}", found, expected),
+            MapLoadError::RaggedHeightRow { row, expected, found } =>
+                write!(f, "map heights row {} has {} tiles but its size says {}", row, found, expected),
+            MapLoadError::SpawnOutOfBounds { x, z } =>
+                write!(f, "spawn point ({}, {}) is outside of the map", x, z),
+            MapLoadError::SpawnOccupied { x, z } =>
+                write!(f, "more than one unit spawns on ({}, {})", x, z),
+            MapLoadError::TooManyItems(name) =>
+                write!(f, "{} starts with more than {} items", name, INVENTORY_SIZE),
+            MapLoadError::BadLevel { name, level } =>
+                write!(f, "{} starts at level {} but levels go from 1 to {}", name, level, MAX_LEVEL),
+            MapLoadError::ObjectiveOutOfBounds { x, z } =>
+                write!(f, "objective tile ({}, {}) is outside of the map", x, z),
+            MapLoadError::NoBoss => write!(f, "objective is to defeat the boss but no unit is a boss"),
+            MapLoadError::MissingDefendUnit(name) => write!(f, "objective is to defend {} but no player unit has that name", name),
+        }
+    }
+}
+
+impl std::error::Error for MapLoadError {}
+
+impl From<std::io::Error> for MapLoadError
+{
+    fn from(err: std::io::Error) -> Self
+    {
+        MapLoadError::Io(err)
+    }
+}
+
+impl From<ron::error::SpannedError> for MapLoadError
+{
+    fn from(err: ron::error::SpannedError) -> Self
+    {
+        MapLoadError::Ron(err)
+    }
+}
+
+impl MapData
+{
+    ///Check that the grid matches the map size and only uses tiles from the tile list.
+    pub fn validate(&self) -> Result<(), MapLoadError>
+    {
+        let (width, height) = self.size;
+        if width == 0 || height == 0
+        {
+            return Err(MapLoadError::EmptyMap);
+        }
+        if self.grid.len() != height
+        {
+            return Err(MapLoadError::RowCount { expected: height, found: self.grid.len() });
+        }
+        for (i, tile) in self.tiles.iter().enumerate()
+        {
+            if self.tiles[..i].iter().any(|other| other.id == tile.id)
+            {
+                return Err(MapLoadError::DuplicateTile(tile.id));
+            }
+        }
+        for (z, row) in self.grid.iter().enumerate()
+        {
+            if row.len() != width
+            {
+                return Err(MapLoadError::RaggedRow { row: z, expected: width, found: row.len() });
+            }
+            for (x, id) in row.iter().enumerate()
+            {
+                if !self.tiles.iter().any(|tile| tile.id == *id)
+                {
+                    return Err(MapLoadError::UnknownTile { x, z, id: *id });
+                }
+            }
+        }
+        if !self.heights.is_empty()
+        {
+            if self.heights.len() != height
+            {
+                return Err(MapLoadError::HeightRowCount { expected: height, found: self.heights.len() });
+            }
+            if let Some((z, row)) = self.heights.iter().enumerate().find(|(_, row)| row.len() != width)
+            {
+                return Err(MapLoadError::RaggedHeightRow { row: z, expected: width, found: row.len() });
+            }
+        }
+        for (i, spawn) in self.units.iter().enumerate()
+        {
+            let (x, z) = spawn.location;
+            if x >= width || z >= height
+            {
+                return Err(MapLoadError::SpawnOutOfBounds { x, z });
+            }
+            if self.units[..i].iter().any(|other| other.location == spawn.location)
+            {
+                return Err(MapLoadError::SpawnOccupied { x, z });
+            }
+            if spawn.items.len() > INVENTORY_SIZE
+            {
+                return Err(MapLoadError::TooManyItems(spawn.name.clone()));
+            }
+            if spawn.level == 0 || spawn.level > MAX_LEVEL
+            {
+                return Err(MapLoadError::BadLevel { name: spawn.name.clone(), level: spawn.level });
+            }
+        }
+        match &self.objective
+        {
+            Objective::Seize((x, z)) | Objective::Defend { target: DefendTarget::Tile((x, z)), .. } if *x >= width || *z >= height =>
+                return Err(MapLoadError::ObjectiveOutOfBounds { x: *x, z: *z }),
+            Objective::Boss if !self.units.iter().any(|unit| unit.boss) =>
+                return Err(MapLoadError::NoBoss),
+            Objective::Defend { target: DefendTarget::Unit(name), .. } if !self.units.iter().any(|unit| unit.team == PLAYER_TEAM && unit.name == *name) =>
+                return Err(MapLoadError::MissingDefendUnit(name.clone())),
+            _ => (),
+        }
+        Ok(())
+    }
+}
+
+#[derive(Default)]
+pub struct MapDataLoader;
+
+impl AssetLoader for MapDataLoader
+{
+    type Asset = MapData;
+    type Settings = ();
+    type Error = MapLoadError;
+
+    async fn load<'a>
+    (
+        &'a self,
+        reader: &'a mut Reader<'_>,
+        _settings: &'a (),
+        _load_context: &'a mut LoadContext<'_>,
+    ) -> Result<MapData, MapLoadError>
+    {
+        let mut bytes = Vec::new();
+        reader.read_to_end(&mut bytes).await?;
+        let map = ron::de::from_bytes::<MapData>(&bytes)?;
+        map.validate()?;
+        Ok(map)
+    }
+
+    fn extensions(&self) -> &[&str]
+    {
+        &["map.ron"]
+    }
+}
+
+///Handle to the map that is loaded for the current battle.
+#[derive(Resource)]
+pub struct MapAsset
+{
+    pub map: Handle<MapData>,
+    ///Asset path the map was loaded from. Saves use this to know which map they belong to.
+    pub path: String,
+}
+
+///Start loading the battle map. Needs to finish before init_map.
+pub fn init_map_asset
+(
+    mut cmd: Commands,
+    asset_server: Res<AssetServer>
+)
+{
+    let path = "maps/test.map.ron";
+    cmd.insert_resource(MapAsset{map: asset_server.load(path), path: path.into()});
+}
+
+#[cfg(test)]
+mod test
+{
+    use super::*;
+
+    fn test_map(grid: Vec<Vec<u32>>) -> MapData
+    {
+        MapData
+        {
+            name: "test".into(),
+            size: (3, 2),
+            tiles: vec![TileData{id: 0, name: "Plain".into(), mv_cost: 1.0, color: (0.0, 0.5, 0.0), defense: 0, avoid: 0, heal: 0}],
+            grid,
+            heights: vec![],
+            units: vec![],
+            objective: Objective::Rout,
+        }
+    }
+
+    #[test]
+    pub fn test_validate()
+    {
+        assert!(test_map(vec![vec![0, 0, 0], vec![0, 0, 0]]).validate().is_ok());
+        assert!(matches!(
+            test_map(vec![vec![0, 0, 0]]).validate(),
+            Err(MapLoadError::RowCount { expected: 2, found: 1 })
+        ));
+        assert!(matches!(
+            test_map(vec![vec![0, 0, 0], vec![0, 0]]).validate(),
+            Err(MapLoadError::RaggedRow { row: 1, expected: 3, found: 2 })
+        ));
+        assert!(matches!(
+            test_map(vec![vec![0, 0, 0], vec![0, 4, 0]]).validate(),
+            Err(MapLoadError::UnknownTile { x: 1, z: 1, id: 4 })
+        ));
+
+        let spawn = |location| UnitSpawn{name: "Test".into(), team: 0, class: "Lord".into(), stats: None, level: 1, growths: None, sprite: None, location, items: vec![], lord: false, boss: false};
+        let mut map = test_map(vec![vec![0, 0, 0], vec![0, 0, 0]]);
+        map.units = vec![spawn((0, 0)), spawn((2, 1))];
+        assert!(map.validate().is_ok());
+        map.units.push(spawn((3, 1)));
+        assert!(matches!(map.validate(), Err(MapLoadError::SpawnOutOfBounds { x: 3, z: 1 })));
+        map.units[2] = spawn((2, 1));
+        assert!(matches!(map.validate(), Err(MapLoadError::SpawnOccupied { x: 2, z: 1 })));
+        map.units[2] = UnitSpawn{level: 0, ..spawn((1, 1))};
+        assert!(matches!(map.validate(), Err(MapLoadError::BadLevel { level: 0, .. })));
+        map.units[2].level = MAX_LEVEL + 1;
+        assert!(matches!(map.validate(), Err(MapLoadError::BadLevel { level, .. }) if level == MAX_LEVEL + 1));
+        map.units[2].level = MAX_LEVEL;
+        assert!(map.validate().is_ok());
+
+        map.heights = vec![vec![0, 1, 2], vec![0, 1]];
+        assert!(matches!(map.validate(), Err(MapLoadError::RaggedHeightRow { row: 1, expected: 3, found: 2 })));
+        map.heights.clear();
+
+        map.units.pop();
+        map.objective = Objective::Seize((1, 2));
+        assert!(matches!(map.validate(), Err(MapLoadError::ObjectiveOutOfBounds { x: 1, z: 2 })));
+        map.objective = Objective::Boss;
+        assert!(matches!(map.validate(), Err(MapLoadError::NoBoss)));
+        map.objective = Objective::Defend { target: DefendTarget::Unit("Test".into()), turns: 3 };
+        assert!(map.validate().is_ok());
+    }
+}
@@ -0,0 +1,448 @@
+use bevy::{color::palettes::css::{DARK_SLATE_GRAY, GRAY, SLATE_GRAY, WHITE}, prelude::*};
+
+use crate::combat::*;
+use crate::input::*;
+use crate::item::*;
+use crate::map::*;
+use crate::replay::*;
+use crate::rng::*;
+use crate::turn::*;
+use crate::unit::*;
+use crate::Player;
+
+/// Which menu is open in Player::ActionMenu.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum MenuKind
+{
+    ///Opened after the selected unit finishes moving.
+    #[default] Action,
+    ///Opened by clicking an empty tile in Player::Field.
+    Field,
+    ///Opened from the action menu to pick which weapon to equip.
+    Items,
+    ///Opened from the field menu.
+    Options,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MenuOption
+{
+    Attack,
+    Item,
+    Wait,
+    Trade,
+    EndTurn,
+    Units,
+    Options,
+    ///Switch between single and true hit rolls.
+    ToggleHitMode,
+    ///Equip the item in this inventory slot.
+    Equip(usize),
+}
+
+impl MenuOption
+{
+    pub fn label(&self) -> &'static str
+    {
+        match self
+        {
+            MenuOption::Attack => "Attack",
+            MenuOption::Item => "Item",
+            MenuOption::Wait => "Wait",
+            MenuOption::Trade => "Trade",
+            MenuOption::EndTurn => "End Turn",
+            MenuOption::Units => "Units",
+            MenuOption::Options => "Options",
+            MenuOption::ToggleHitMode => "Hit",
+            MenuOption::Equip(_) => "Equip",
+        }
+    }
+}
+
+/// The menu that is, or is about to be, shown in Player::ActionMenu.
+#[derive(Resource, Default)]
+pub struct ActiveMenu
+{
+    pub kind: MenuKind,
+    options: Vec<(MenuOption, bool)>,
+    cursor: usize,
+}
+
+impl ActiveMenu
+{
+    ///Move the cursor by `step` entries, skipping disabled ones and wrapping around.
+    fn move_cursor(&mut self, step: isize)
+    {
+        let len = self.options.len() as isize;
+        let mut next = self.cursor as isize;
+        for _ in 0..len
+        {
+            next = (next + step).rem_euclid(len);
+            if self.options[next as usize].1
+            {
+                self.cursor = next as usize;
+                return;
+            }
+        }
+    }
+}
+
+#[derive(Component)]
+pub struct MenuRoot;
+
+#[derive(Component)]
+pub struct MenuButton(usize);
+
+///Sent when the player confirms an entry in the open menu.
+#[derive(Event)]
+pub struct MenuChoice(pub MenuOption);
+
+///Sent to rebuild the open menu after its kind changed without leaving Player::ActionMenu.
+#[derive(Event)]
+pub struct RefreshMenu;
+
+const MENU_SELECTED: Srgba = SLATE_GRAY;
+const MENU_IDLE: Srgba = DARK_SLATE_GRAY;
+
+///Build the options for the requested menu and show it.
+pub fn spawn_menu
+(
+    mut cmd: Commands,
+    mut menu: ResMut<ActiveMenu>,
+    sel_qry: Query<&SelectedUnit>,
+    unit_qry: Query<(&Location, &Team, &Inventory), With<IsUnit>>,
+    rng: Res<GameRng>
+)
+{
+    let selected = sel_qry.single().selected_unit.and_then(|unit| unit_qry.get(unit).ok());
+    menu.options = match menu.kind
+    {
+        MenuKind::Action =>
+        {
+            let mut can_attack = false;
+            if let Some((&loc, team, inventory)) = selected
+            {
+                for (&other_loc, other_team, _) in &unit_qry
+                {
+                    can_attack |= other_team.0 != team.0 && inventory.in_range(distance(loc, other_loc));
+                }
+            }
+            let has_items = selected.is_some_and(|(_, _, inventory)| !inventory.items.is_empty());
+            vec![
+                (MenuOption::Attack, can_attack),
+                (MenuOption::Item, has_items),
+                (MenuOption::Wait, true),
+                //Greyed out until trading is in.
+                (MenuOption::Trade, false),
+            ]
+        },
+        MenuKind::Field => vec![
+            (MenuOption::EndTurn, true),
+            //Greyed out until there's a roster panel.
+            (MenuOption::Units, false),
+            (MenuOption::Options, true),
+        ],
+        MenuKind::Items => selected
+            .map(|(_, _, inventory)| (0..inventory.items.len()).map(|i| (MenuOption::Equip(i), true)).collect())
+            .unwrap_or_default(),
+        MenuKind::Options => vec![
+            (MenuOption::ToggleHitMode, true),
+        ],
+    };
+    let labels = menu.options.iter()
+        .map(|(option, _)| match (option, selected)
+        {
+            (MenuOption::Equip(i), Some((_, _, inventory))) =>
+            {
+                let item = &inventory.items[*i];
+                let mark = if inventory.equipped == Some(*i) {"E "} else {""};
+                format!("{}{} {}", mark, item.weapon.name, item.uses)
+            },
+            (MenuOption::ToggleHitMode, _) => match rng.hit_mode()
+            {
+                HitMode::Single => "Hit: Single".to_string(),
+                HitMode::TrueHit => "Hit: True".to_string(),
+            },
+            _ => option.label().to_string(),
+        })
+        .collect::<Vec<_>>();
+    menu.cursor = menu.options.iter().position(|(_, enabled)| *enabled).unwrap_or(0);
+
+    cmd.spawn((NodeBundle
+    {
+        style: Style
+        {
+            position_type: PositionType::Absolute,
+            right: Val::Px(40.0),
+            top: Val::Px(40.0),
+            flex_direction: FlexDirection::Column,
+            row_gap: Val::Px(4.0),
+            padding: UiRect::all(Val::Px(6.0)),
+            ..default()
+        },
+        background_color: BackgroundColor(Color::srgba(0.0, 0.0, 0.0, 0.6)),
+        ..default()
+    },
+    MenuRoot,
+    )).with_children(|root|
+    {
+        for (i, ((_, enabled), label)) in menu.options.iter().zip(labels).enumerate()
+        {
+            root.spawn((ButtonBundle
+            {
+                style: Style
+                {
+                    width: Val::Px(200.0),
+                    padding: UiRect::axes(Val::Px(10.0), Val::Px(4.0)),
+                    ..default()
+                },
+                background_color: BackgroundColor(Color::Srgba(MENU_IDLE)),
+                ..default()
+            },
+            MenuButton(i),
+            )).with_children(|button|
+            {
+                button.spawn(TextBundle::from_section
+                (
+                    label,
+                    TextStyle
+                    {
+                        font_size: 24.0,
+                        color: Color::Srgba(if *enabled {WHITE} else {GRAY}),
+                        ..default()
+                    }
+                ));
+            });
+        }
+    });
+}
+
+pub fn despawn_menu
+(
+    mut cmd: Commands,
+    menu_qry: Query<Entity, With<MenuRoot>>
+)
+{
+    for menu in &menu_qry
+    {
+        cmd.entity(menu).despawn_recursive();
+    }
+}
+
+///Move through the menu with the arrow keys or by hovering, and confirm with Enter, Space or a click.
+pub fn navigate_menu
+(
+    mut menu: ResMut<ActiveMenu>,
+    keys: Res<ButtonInput<KeyCode>>,
+    button_qry: Query<(&Interaction, &MenuButton), Changed<Interaction>>,
+    mut menu_choice: EventWriter<MenuChoice>,
+    mut cancel: EventWriter<Cancel>
+)
+{
+    if menu.options.is_empty()
+    {
+        return;
+    }
+    if keys.just_pressed(KeyCode::ArrowUp)
+    {
+        menu.move_cursor(-1);
+    }
+    if keys.just_pressed(KeyCode::ArrowDown)
+    {
+        menu.move_cursor(1);
+    }
+    let mut confirm = keys.just_pressed(KeyCode::Enter) || keys.just_pressed(KeyCode::Space);
+    for (interaction, button) in &button_qry
+    {
+        if !menu.options[button.0].1
+        {
+            continue;
+        }
+        match interaction
+        {
+            Interaction::Hovered => menu.cursor = button.0,
+            Interaction::Pressed =>
+            {
+                menu.cursor = button.0;
+                confirm = true;
+            },
+            Interaction::None => {},
+        }
+    }
+    if confirm
+    {
+        let (option, enabled) = menu.options[menu.cursor];
+        if enabled
+        {
+            menu_choice.send(MenuChoice(option));
+        }
+    }
+    if keys.just_pressed(KeyCode::Escape)
+    {
+        cancel.send(Cancel);
+    }
+}
+
+pub fn highlight_menu
+(
+    menu: Res<ActiveMenu>,
+    mut button_qry: Query<(&MenuButton, &mut BackgroundColor)>
+)
+{
+    for (button, mut color) in button_qry.iter_mut()
+    {
+        *color = BackgroundColor(Color::Srgba(if button.0 == menu.cursor {MENU_SELECTED} else {MENU_IDLE}));
+    }
+}
+
+///Carry out whatever the player picked from the menu.
+pub fn handle_menu_choice
+(
+    mut cmd: Commands,
+    mut menu_choice: EventReader<MenuChoice>,
+    mut menu: ResMut<ActiveMenu>,
+    sel_qry: Query<&SelectedUnit>,
+    mut inventory_qry: Query<&mut Inventory>,
+    mut next_state: ResMut<NextState<Player>>,
+    mut end_phase: EventWriter<EndPhase>,
+    mut refresh_menu: EventWriter<RefreshMenu>,
+    mut rng: ResMut<GameRng>,
+    id_qry: Query<&UnitID>,
+    mut log: ResMut<CommandLog>
+)
+{
+    for choice in menu_choice.read()
+    {
+        let selected = sel_qry.single().selected_unit;
+        match choice.0
+        {
+            MenuOption::Attack => next_state.set(Player::Action),
+            MenuOption::Wait =>
+            {
+                if let Some(unit) = selected
+                {
+                    cmd.entity(unit).insert(Acted);
+                    if let Ok(id) = id_qry.get(unit)
+                    {
+                        log.push(BattleCommand::Wait{unit: *id});
+                    }
+                }
+                next_state.set(Player::Field);
+            },
+            MenuOption::Item =>
+            {
+                menu.kind = MenuKind::Items;
+                refresh_menu.send(RefreshMenu);
+            },
+            MenuOption::Equip(i) =>
+            {
+                if let Some((mut inventory, id)) = selected.and_then(|unit| Some((inventory_qry.get_mut(unit).ok()?, id_qry.get(unit).ok()?)))
+                {
+                    inventory.equip(i);
+                    log.push(BattleCommand::Equip{unit: *id, slot: i});
+                }
+                menu.kind = MenuKind::Action;
+                refresh_menu.send(RefreshMenu);
+            },
+            MenuOption::Trade => println!("Trading isn't in yet."),
+            MenuOption::EndTurn =>
+            {
+                end_phase.send(EndPhase);
+            },
+            MenuOption::Units => println!("The unit roster isn't in yet."),
+            MenuOption::Options =>
+            {
+                menu.kind = MenuKind::Options;
+                refresh_menu.send(RefreshMenu);
+            },
+            MenuOption::ToggleHitMode =>
+            {
+                let hit_mode = match rng.hit_mode()
+                {
+                    HitMode::Single => HitMode::TrueHit,
+                    HitMode::TrueHit => HitMode::Single,
+                };
+                rng.set_hit_mode(hit_mode);
+                log.push(BattleCommand::SetHitMode(hit_mode));
+                refresh_menu.send(RefreshMenu);
+            },
+        }
+    }
+}
+
+///Outline the enemies the selected unit can attack from where it's standing.
+pub fn render_attack_targets
+(
+    mut gizmo: Gizmos,
+    sel_qry: Query<&SelectedUnit>,
+    unit_qry: Query<(&Location, &Team), With<IsUnit>>,
+    inventory_qry: Query<&Inventory>,
+    height_qry: Query<&HeightMap>
+)
+{
+    let Some(unit) = sel_qry.single().selected_unit else {return};
+    let Ok(heights) = height_qry.get_single() else {return};
+    let (Ok((&loc, team)), Ok(inventory)) = (unit_qry.get(unit), inventory_qry.get(unit)) else {return};
+    for (&other_loc, other_team) in &unit_qry
+    {
+        if other_team.0 != team.0 && inventory.in_range(distance(loc, other_loc))
+        {
+            gizmo.rect
+            (
+                Vec3::new(other_loc.0 as f32, tile_surface(heights.at(other_loc)) + 0.05, other_loc.1 as f32),
+                Quat::from_axis_angle(Vec3::X, std::f32::consts::PI/2.0),
+                Vec2::splat(0.9),
+                Color::srgb(1.0, 0.2, 0.2)
+            );
+        }
+    }
+}
+
+///Attack whichever enemy the player clicks, as long as it's in range.
+pub fn select_attack_target
+(
+    sel_qry: Query<&SelectedUnit>,
+    unit_qry: Query<(&Location, &Team), With<IsUnit>>,
+    inventory_qry: Query<&Inventory>,
+    mut unit_on_tile: EventReader<UnitOnTile>,
+    mut attack_unit: EventWriter<AttackUnit>
+)
+{
+    for event in unit_on_tile.read()
+    {
+        let Some(unit) = sel_qry.single().selected_unit else {continue};
+        let (Ok((&loc, team)), Ok(inventory)) = (unit_qry.get(unit), inventory_qry.get(unit)) else {continue};
+        let Some(target) = event.0 else {continue};
+        match unit_qry.get(target)
+        {
+            Ok((&target_loc, target_team)) if target_team.0 != team.0 && inventory.in_range(distance(loc, target_loc)) =>
+            {
+                attack_unit.send(AttackUnit{attacker: unit, defender: target});
+            },
+            _ => println!("Play negative noise. Can't attack that."),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test
+{
+    use super::*;
+
+    #[test]
+    pub fn test_menu_cursor_skips_disabled()
+    {
+        let mut menu = ActiveMenu
+        {
+            kind: MenuKind::Action,
+            options: vec![(MenuOption::Attack, false), (MenuOption::Item, true), (MenuOption::Wait, true), (MenuOption::Trade, false)],
+            cursor: 1,
+        };
+        menu.move_cursor(1);
+        assert_eq!(menu.cursor, 2);
+        menu.move_cursor(1);
+        assert_eq!(menu.cursor, 1);
+        menu.move_cursor(-1);
+        assert_eq!(menu.cursor, 2);
+    }
+}
@@ -0,0 +1,232 @@
+use bevy::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::map::*;
+use crate::shared::*;
+use crate::turn::*;
+use crate::unit::*;
+use crate::GameState;
+
+/// How a map is won. Losing a lord or the whole army is always a defeat.
+#[derive(Deserialize, Clone, Debug, Default, PartialEq)]
+pub enum Objective
+{
+    ///Defeat every unit that isn't on the player's team.
+    #[default] Rout,
+    ///Defeat every unit flagged as a boss.
+    Boss,
+    ///Move a lord onto the tile. Any player unit can seize if the player has no lords left.
+    Seize((usize, usize)),
+    ///Last until the end of the given turn.
+    Survive(u32),
+    ///Keep the target safe until the end of the given turn.
+    Defend{target: DefendTarget, turns: u32},
+}
+
+/// What has to be kept safe in a `Defend` objective.
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+pub enum DefendTarget
+{
+    ///A unit by name. Defeat if it dies.
+    Unit(String),
+    ///Defeat if an enemy stands on the tile.
+    Tile((usize, usize)),
+}
+
+/// A unit the battle can't be lost without.
+#[derive(Component)]
+pub struct Lord;
+
+/// A unit that has to be defeated for a `Boss` objective.
+#[derive(Component)]
+pub struct Boss;
+
+/// A unit that died during the battle.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct FallenUnit
+{
+    pub name: String,
+    pub team: u32,
+    pub lord: bool,
+}
+
+/// The objective of the map, kept on the map entity.
+#[derive(Component, Clone, Debug)]
+pub struct MapObjective(pub Objective);
+
+///Every unit that has died so far, in the order they fell.
+#[derive(Resource, Default)]
+pub struct Fallen(pub Vec<FallenUnit>);
+
+/// What the objective check needs to know about a living unit.
+#[derive(Clone, Debug)]
+pub struct UnitStatus
+{
+    pub name: String,
+    pub team: u32,
+    pub loc: Location,
+    pub lord: bool,
+    pub boss: bool,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Outcome
+{
+    Victory,
+    Defeat,
+}
+
+///How the battle ended, shown on the result screen.
+#[derive(Resource, Clone, Debug)]
+pub struct BattleResult
+{
+    pub outcome: Outcome,
+    pub turn: u32,
+    ///Names of the player's units that died.
+    pub losses: Vec<String>,
+}
+
+///Check the objective against the units still alive. Defeat wins out if both happen at once.
+pub fn battle_outcome(objective: &Objective, turn: u32, units: &[UnitStatus], fallen: &[FallenUnit]) -> Option<Outcome>
+{
+    let players = units.iter().filter(|unit| unit.team == PLAYER_TEAM);
+    let enemies = units.iter().filter(|unit| unit.team != PLAYER_TEAM);
+
+    let lord_fell = fallen.iter().any(|unit| unit.team == PLAYER_TEAM && unit.lord);
+    let target_lost = match objective
+    {
+        Objective::Defend{target: DefendTarget::Unit(name), ..} => fallen.iter().any(|unit| unit.team == PLAYER_TEAM && unit.name == *name),
+        Objective::Defend{target: DefendTarget::Tile(tile), ..} => enemies.clone().any(|unit| unit.loc == Location(tile.0, tile.1)),
+        _ => false,
+    };
+    if lord_fell || target_lost || players.clone().next().is_none()
+    {
+        return Some(Outcome::Defeat);
+    }
+
+    let won = match objective
+    {
+        Objective::Rout => enemies.clone().next().is_none(),
+        Objective::Boss => !units.iter().any(|unit| unit.boss),
+        Objective::Seize(tile) =>
+        {
+            let has_lord = players.clone().any(|unit| unit.lord);
+            players.clone().any(|unit| unit.loc == Location(tile.0, tile.1) && (unit.lord || !has_lord))
+        },
+        Objective::Survive(turns) | Objective::Defend{turns, ..} => turn > *turns,
+    };
+    won.then_some(Outcome::Victory)
+}
+
+///End the battle once the map's objective is met or failed.
+pub fn check_objectives
+(
+    mut cmd: Commands,
+    map_qry: Query<&MapObjective>,
+    unit_qry: Query<(&ObjName, &Team, &Location, Has<Lord>, Has<Boss>), With<IsUnit>>,
+    turn: Res<Turn>,
+    fallen: Res<Fallen>,
+    mut next_state: ResMut<NextState<GameState>>
+)
+{
+    let Ok(objective) = map_qry.get_single() else {return};
+    let units = unit_qry.iter()
+        .map(|(name, team, loc, lord, boss)| UnitStatus{name: name.0.clone(), team: team.0, loc: *loc, lord, boss})
+        .collect::<Vec<_>>();
+    let Some(outcome) = battle_outcome(&objective.0, turn.0, &units, &fallen.0) else {return};
+
+    let losses = fallen.0.iter()
+        .filter(|unit| unit.team == PLAYER_TEAM)
+        .map(|unit| unit.name.clone())
+        .collect();
+    cmd.insert_resource(BattleResult{outcome, turn: turn.0, losses});
+    next_state.set(GameState::Result);
+}
+
+#[derive(Component)]
+pub struct ResultScreen;
+
+pub fn spawn_result_screen
+(
+    mut cmd: Commands,
+    result: Res<BattleResult>
+)
+{
+    let (title, color) = match result.outcome
+    {
+        Outcome::Victory => ("Victory!", Color::srgb(1.0, 0.85, 0.3)),
+        Outcome::Defeat => ("Defeat...", Color::srgb(0.8, 0.2, 0.2)),
+    };
+    let losses = if result.losses.is_empty() {"none".into()} else {result.losses.join(", ")};
+    let lines =
+    [
+        (title.to_string(), 64.0, color),
+        (format!("Turns: {}", result.turn), 28.0, Color::WHITE),
+        (format!("Losses: {}", losses), 28.0, Color::WHITE),
+    ];
+
+    cmd.spawn((NodeBundle
+    {
+        style: Style
+        {
+            width: Val::Percent(100.0),
+            height: Val::Percent(100.0),
+            flex_direction: FlexDirection::Column,
+            justify_content: JustifyContent::Center,
+            align_items: AlignItems::Center,
+            row_gap: Val::Px(12.0),
+            ..default()
+        },
+        background_color: BackgroundColor(Color::srgba(0.0, 0.0, 0.0, 0.7)),
+        ..default()
+    },
+    ResultScreen,
+    )).with_children(|root|
+    {
+        for (text, font_size, color) in lines
+        {
+            root.spawn(TextBundle::from_section(text, TextStyle{font_size, color, ..default()}));
+        }
+    });
+}
+
+#[cfg(test)]
+mod test
+{
+    use super::*;
+
+    fn unit(name: &str, team: u32, loc: (usize, usize)) -> UnitStatus
+    {
+        UnitStatus{name: name.into(), team, loc: Location(loc.0, loc.1), lord: false, boss: false}
+    }
+
+    #[test]
+    pub fn test_battle_outcome()
+    {
+        let lord = UnitStatus{lord: true, ..unit("Martin", 0, (1, 1))};
+        let boss = UnitStatus{boss: true, ..unit("Gareth", 1, (5, 5))};
+        let units = vec![lord.clone(), unit("Alan", 0, (2, 2)), unit("Brigand", 1, (3, 3)), boss.clone()];
+
+        assert_eq!(battle_outcome(&Objective::Rout, 1, &units, &[]), None);
+        assert_eq!(battle_outcome(&Objective::Rout, 1, &units[..2], &[]), Some(Outcome::Victory));
+        assert_eq!(battle_outcome(&Objective::Boss, 1, &units[..3], &[]), Some(Outcome::Victory));
+        assert_eq!(battle_outcome(&Objective::Survive(3), 3, &units, &[]), None);
+        assert_eq!(battle_outcome(&Objective::Survive(3), 4, &units, &[]), Some(Outcome::Victory));
+
+        //Only the lord can seize while one is alive.
+        assert_eq!(battle_outcome(&Objective::Seize((2, 2)), 1, &units, &[]), None);
+        assert_eq!(battle_outcome(&Objective::Seize((1, 1)), 1, &units, &[]), Some(Outcome::Victory));
+        assert_eq!(battle_outcome(&Objective::Seize((2, 2)), 1, &units[1..], &[]), Some(Outcome::Victory));
+
+        let lord_fell = [FallenUnit{name: "Martin".into(), team: 0, lord: true}];
+        assert_eq!(battle_outcome(&Objective::Rout, 1, &units[1..2], &lord_fell), Some(Outcome::Defeat));
+        assert_eq!(battle_outcome(&Objective::Rout, 1, &units[2..], &[]), Some(Outcome::Defeat));
+
+        let defend_alan = Objective::Defend{target: DefendTarget::Unit("Alan".into()), turns: 5};
+        let alan_fell = [FallenUnit{name: "Alan".into(), team: 0, lord: false}];
+        assert_eq!(battle_outcome(&defend_alan, 2, &units, &alan_fell), Some(Outcome::Defeat));
+        assert_eq!(battle_outcome(&defend_alan, 6, &units, &[]), Some(Outcome::Victory));
+        let defend_tile = Objective::Defend{target: DefendTarget::Tile((3, 3)), turns: 5};
+        assert_eq!(battle_outcome(&defend_tile, 6, &units, &[]), Some(Outcome::Defeat));
+    }
+}
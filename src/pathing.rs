@@ -0,0 +1,351 @@
+use std::{cmp::Ordering, collections::BinaryHeap};
+
+use bevy::{prelude::*, utils::{HashMap, HashSet}};
+
+use crate::class::*;
+use crate::item::*;
+use crate::map::*;
+use crate::unit::*;
+
+///Slack given to movement budgets so float costs like 5 * 0.8 still fit in a budget of 4.
+const COST_EPSILON: f32 = 0.001;
+
+///Extra movement it costs to climb up a single height level.
+pub const CLIMB_COST: f32 = 1.0;
+
+///Largest height difference a unit can step across. Anything steeper is a cliff that only flyers can cross.
+pub const MAX_CLIMB: u32 = 1;
+
+/// How a tile was reached while computing a movement range.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PathNode
+{
+    pub cost: f32,
+    pub prev: Option<Location>,
+}
+
+/// The tiles the selected unit can reach this turn.
+#[derive(Resource, Default)]
+pub struct MovementRange
+{
+    pub unit: Option<Entity>,
+    pub origin: Option<Location>,
+    pub reachable: HashMap<Location, PathNode>,
+    ///Tiles outside of `reachable` that the unit could attack after moving.
+    pub attackable: HashSet<Location>,
+}
+
+impl MovementRange
+{
+    pub fn contains(&self, loc: &Location) -> bool
+    {
+        self.reachable.contains_key(loc)
+    }
+
+    ///The tiles walked from the origin to `dest`, including both ends.
+    pub fn path_to(&self, dest: Location) -> Option<Vec<Location>>
+    {
+        path_through(&self.reachable, dest)
+    }
+}
+
+///Follow the `prev` links back from `dest` to get the path from the start of a `reachable_tiles` search, including both ends.
+pub fn path_through(reachable: &HashMap<Location, PathNode>, dest: Location) -> Option<Vec<Location>>
+{
+    let mut path = vec![dest];
+    let mut node = reachable.get(&dest)?;
+    while let Some(prev) = node.prev
+    {
+        path.push(prev);
+        node = &reachable[&prev];
+    }
+    path.reverse();
+    Some(path)
+}
+
+#[derive(PartialEq)]
+struct Frontier
+{
+    cost: f32,
+    loc: Location,
+}
+
+impl Eq for Frontier {}
+
+impl Ord for Frontier
+{
+    //Reversed so the BinaryHeap pops the cheapest tile first. Ties are broken by position so results are stable.
+    fn cmp(&self, other: &Self) -> Ordering
+    {
+        other.cost.total_cmp(&self.cost)
+            .then_with(|| (other.loc.1, other.loc.0).cmp(&(self.loc.1, self.loc.0)))
+    }
+}
+
+impl PartialOrd for Frontier
+{
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering>
+    {
+        Some(self.cmp(other))
+    }
+}
+
+///The orthogonal neighbours of a tile that are inside the map.
+pub fn neighbours(loc: Location, map_size: &MapSize) -> Vec<Location>
+{
+    let mut out = Vec::with_capacity(4);
+    if loc.0 > 0 { out.push(Location(loc.0 - 1, loc.1)) }
+    if loc.1 > 0 { out.push(Location(loc.0, loc.1 - 1)) }
+    if loc.0 + 1 < map_size.0 { out.push(Location(loc.0 + 1, loc.1)) }
+    if loc.1 + 1 < map_size.1 { out.push(Location(loc.0, loc.1 + 1)) }
+    out
+}
+
+///Extra cost of stepping from a tile of height `from` onto one of height `to`, or None if the step is a cliff.
+pub fn climb_cost(from: u32, to: u32, movement_type: MovementType) -> Option<f32>
+{
+    if movement_type == MovementType::Flying
+    {
+        return Some(0.0);
+    }
+    if from.abs_diff(to) > MAX_CLIMB
+    {
+        return None;
+    }
+    Some(to.saturating_sub(from) as f32 * CLIMB_COST)
+}
+
+///Dijkstra over the map grid starting at `start`.
+///
+///`enter_cost` gives the cost of stepping from the first tile onto the second, and `is_blocked` marks tiles that can't be entered at all, like ones held by enemies.
+///Every tile whose total cost fits in `budget` is returned along with the tile it was reached from.
+pub fn reachable_tiles
+(
+    start: Location,
+    budget: f32,
+    map_size: &MapSize,
+    enter_cost: impl Fn(Location, Location) -> f32,
+    is_blocked: impl Fn(Location) -> bool,
+) -> HashMap<Location, PathNode>
+{
+    let mut reached: HashMap<Location, PathNode> = HashMap::new();
+    let mut frontier = BinaryHeap::new();
+    reached.insert(start, PathNode{cost: 0.0, prev: None});
+    frontier.push(Frontier{cost: 0.0, loc: start});
+
+    while let Some(Frontier{cost, loc}) = frontier.pop()
+    {
+        if reached.get(&loc).is_some_and(|node| node.cost < cost)
+        {
+            continue;
+        }
+        for next in neighbours(loc, map_size)
+        {
+            if is_blocked(next)
+            {
+                continue;
+            }
+            let next_cost = cost + enter_cost(loc, next);
+            if next_cost > budget + COST_EPSILON
+            {
+                continue;
+            }
+            if reached.get(&next).map_or(true, |node| next_cost < node.cost)
+            {
+                reached.insert(next, PathNode{cost: next_cost, prev: Some(loc)});
+                frontier.push(Frontier{cost: next_cost, loc: next});
+            }
+        }
+    }
+    reached
+}
+
+///Every tile within `min..=max` tiles (manhattan distance) of one of the `from` tiles, minus the `from` tiles themselves.
+pub fn attack_tiles
+(
+    from: impl IntoIterator<Item = Location>,
+    (min, max): (usize, usize),
+    map_size: &MapSize,
+    exclude: impl Fn(Location) -> bool,
+) -> HashSet<Location>
+{
+    let mut out = HashSet::new();
+    for loc in from
+    {
+        let (x, z) = (loc.0 as isize, loc.1 as isize);
+        let max = max as isize;
+        for dz in -max..=max
+        {
+            for dx in -max..=max
+            {
+                let dist = (dx.abs() + dz.abs()) as usize;
+                if dist < min || dist > max as usize
+                {
+                    continue;
+                }
+                let Some(target) = Location::from_signed(x + dx, z + dz, map_size) else {continue};
+                if !exclude(target)
+                {
+                    out.insert(target);
+                }
+            }
+        }
+    }
+    out
+}
+
+///The tiles a unit can reach on the current map. Enemy units block movement, allies can be passed through, and cliffs can't be crossed.
+pub fn unit_reachable_tiles
+(
+    loc: Location,
+    movement: &Movement,
+    team: &Team,
+    class: &Class,
+    (map_size, tile_map, tile_list, unit_map, heights): (&MapSize, &TileMap, &TileList, &UnitMap, &HeightMap),
+    team_qry: &Query<&Team>
+) -> HashMap<Location, PathNode>
+{
+    reachable_tiles
+    (
+        loc,
+        movement.0,
+        map_size,
+        |from, to| match climb_cost(heights.at(from), heights.at(to), class.0.movement_type)
+        {
+            Some(climb) => class.0.terrain_cost(&tile_list.0[&tile_map.0[to.1][to.0]]) + climb,
+            None => f32::INFINITY,
+        },
+        |at| unit_map.get(at)
+            .and_then(|other| team_qry.get(other).ok())
+            .is_some_and(|other_team| other_team.0 != team.0)
+    )
+}
+
+///Recompute the movement range whenever the selected unit or its location no longer matches the stored range.
+pub fn update_movement_range
+(
+    mut range: ResMut<MovementRange>,
+    sel_qry: Query<&SelectedUnit>,
+    map_qry: Query<(&MapSize, &TileMap, &TileList, &UnitMap, &HeightMap)>,
+    unit_qry: Query<(&Location, &Movement, &Team, &Class, &Inventory)>,
+    team_qry: Query<&Team>
+)
+{
+    let Ok(selected) = sel_qry.get_single() else {return};
+    let Some(unit) = selected.selected_unit else {return};
+    let Ok((&loc, movement, team, class, inventory)) = unit_qry.get(unit) else {return};
+    if range.unit == Some(unit) && range.origin == Some(loc)
+    {
+        return;
+    }
+    let map = map_qry.single();
+    let (map_size, _, _, unit_map, _) = map;
+
+    range.reachable = unit_reachable_tiles(loc, movement, team, class, map, &team_qry);
+    //Attacks can only be made from tiles the unit can actually stop on.
+    let standable = range.reachable.keys()
+        .copied()
+        .filter(|at| *at == loc || unit_map.get(*at).is_none())
+        .collect::<Vec<_>>();
+    range.attackable = match inventory.attack_range()
+    {
+        Some(attack_range) => attack_tiles(standable, attack_range, map_size, |at| range.reachable.contains_key(&at)),
+        None => HashSet::new(),
+    };
+    range.unit = Some(unit);
+    range.origin = Some(loc);
+}
+
+pub fn clear_movement_range(mut range: ResMut<MovementRange>)
+{
+    *range = MovementRange::default();
+}
+
+#[cfg(test)]
+mod test
+{
+    use super::*;
+
+    #[test]
+    pub fn test_reachable_tiles()
+    {
+        let size = MapSize(5, 5);
+        //Column 2 is a wall except for (2, 4), which is held by an enemy.
+        let reached = reachable_tiles
+        (
+            Location(0, 0),
+            3.0,
+            &size,
+            |_, at| if at.0 == 2 && at.1 < 4 {99.0} else {1.0},
+            |at| at == Location(2, 4)
+        );
+        assert_eq!(reached[&Location(0, 0)].cost, 0.0);
+        assert_eq!(reached[&Location(1, 2)].cost, 3.0);
+        assert_eq!(reached[&Location(1, 0)].prev, Some(Location(0, 0)));
+        assert!(!reached.contains_key(&Location(2, 0)));
+        assert!(!reached.contains_key(&Location(0, 4)));
+
+        let reached = reachable_tiles(Location(0, 0), 4.0, &size, |_, _| 1.0, |at| at == Location(0, 4));
+        assert!(reached.contains_key(&Location(1, 3)));
+        assert!(!reached.contains_key(&Location(0, 4)));
+    }
+
+    #[test]
+    pub fn test_attack_tiles()
+    {
+        let size = MapSize(4, 4);
+        let tiles = attack_tiles([Location(0, 0), Location(1, 0)], (1, 1), &size, |at| at == Location(0, 0) || at == Location(1, 0));
+        assert_eq!(tiles.len(), 3);
+        assert!(tiles.contains(&Location(2, 0)));
+        assert!(tiles.contains(&Location(0, 1)));
+        assert!(tiles.contains(&Location(1, 1)));
+
+        let tiles = attack_tiles([Location(1, 1)], (2, 2), &size, |_| false);
+        assert!(!tiles.contains(&Location(1, 2)));
+        assert!(tiles.contains(&Location(3, 1)));
+        assert!(tiles.contains(&Location(2, 2)));
+    }
+
+    #[test]
+    pub fn test_path_to()
+    {
+        let mut range = MovementRange::default();
+        range.reachable = reachable_tiles(Location(0, 0), 4.0, &MapSize(3, 3), |_, at| if at == Location(1, 0) {99.0} else {1.0}, |_| false);
+        assert_eq!(range.path_to(Location(0, 0)), Some(vec![Location(0, 0)]));
+        let path = range.path_to(Location(2, 0)).unwrap();
+        assert_eq!(path.len(), 5);
+        assert_eq!(path[0], Location(0, 0));
+        assert_eq!(path[4], Location(2, 0));
+        assert!(!path.contains(&Location(1, 0)));
+        assert_eq!(range.path_to(Location(5, 5)), None);
+    }
+
+    #[test]
+    pub fn test_fractional_costs()
+    {
+        let reached = reachable_tiles(Location(0, 0), 4.0, &MapSize(6, 1), |_, _| 0.8, |_| false);
+        assert!(reached.contains_key(&Location(5, 0)));
+    }
+
+    #[test]
+    pub fn test_climbing()
+    {
+        assert_eq!(climb_cost(0, 1, MovementType::Foot), Some(1.0));
+        assert_eq!(climb_cost(1, 0, MovementType::Foot), Some(0.0));
+        assert_eq!(climb_cost(0, 2, MovementType::Armored), None);
+        assert_eq!(climb_cost(3, 0, MovementType::Flying), Some(0.0));
+
+        //A ramp up to a plateau that is a cliff from the other side.
+        let heights = [0, 1, 2, 2, 0];
+        let reached = reachable_tiles
+        (
+            Location(0, 0),
+            10.0,
+            &MapSize(5, 1),
+            |from, to| climb_cost(heights[from.0], heights[to.0], MovementType::Foot).map_or(f32::INFINITY, |climb| 1.0 + climb),
+            |_| false
+        );
+        assert_eq!(reached[&Location(3, 0)].cost, 5.0);
+        assert!(!reached.contains_key(&Location(4, 0)));
+    }
+}
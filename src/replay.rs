@@ -0,0 +1,550 @@
+use bevy::{prelude::*, utils::HashMap};
+use bevy_sprite3d::*;
+use serde::{Deserialize, Serialize};
+
+use crate::animation::*;
+use crate::class::*;
+use crate::combat::*;
+use crate::input::*;
+use crate::item::*;
+use crate::map::*;
+use crate::map_data::*;
+use crate::objective::*;
+use crate::pathing::*;
+use crate::rng::*;
+use crate::turn::*;
+use crate::unit::*;
+use crate::{Phase, Player};
+
+/// A single gameplay decision, by either side.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum BattleCommand
+{
+    MoveUnit { unit: UnitID, to: Location },
+    Equip { unit: UnitID, slot: usize },
+    ///`weapon` is the inventory slot that was equipped for the attack.
+    Attack { unit: UnitID, target: UnitID, weapon: Option<usize> },
+    Wait { unit: UnitID },
+    ///The player switched how hit chances are rolled.
+    SetHitMode(HitMode),
+    ///The phase that was running ended.
+    EndTurn,
+}
+
+/// Every decision made this battle, in order, along with the rng state the battle started from.
+///
+/// Starting from the map's units and replaying these reproduces the battle exactly.
+#[derive(Resource, Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct CommandLog
+{
+    ///Missing for saves made before battles were logged. Those battles can't be replayed.
+    #[serde(default)]
+    pub start: Option<RngState>,
+    pub commands: Vec<BattleCommand>,
+}
+
+impl CommandLog
+{
+    pub fn push(&mut self, command: BattleCommand)
+    {
+        self.commands.push(command);
+    }
+
+    ///Drop the unit's latest move from the current phase, for when the move is undone.
+    pub fn remove_move(&mut self, unit: UnitID)
+    {
+        let latest = self.commands.iter()
+            .rposition(|command| matches!(command, BattleCommand::EndTurn) || matches!(command, BattleCommand::MoveUnit{unit: moved, ..} if *moved == unit));
+        if let Some(i) = latest.filter(|i| self.commands[*i] != BattleCommand::EndTurn)
+        {
+            self.commands.remove(i);
+        }
+    }
+}
+
+/// Where each player action this phase started in the command log, so the phase can be rewound one action at a time.
+#[derive(Resource, Default)]
+pub struct RewindPoints(Vec<usize>);
+
+impl RewindPoints
+{
+    ///Forget every point, for when the log they point into is replaced or its phase is over.
+    pub fn clear(&mut self)
+    {
+        self.0.clear();
+    }
+}
+
+///Sent to take back the last action of the current player phase.
+#[derive(Event)]
+pub struct RewindTurn;
+
+/// What a replay is waiting on before it applies its next command.
+#[derive(Clone, Debug, PartialEq)]
+enum ReplayWait
+{
+    Ready,
+    Combat,
+    ///A unit is walking a logged move.
+    Walk(Entity),
+    Phase(Phase),
+}
+
+/// A replay in progress. Gameplay input and the AI are off while this exists.
+#[derive(Resource)]
+pub struct Replay
+{
+    commands: Vec<BattleCommand>,
+    next: usize,
+    wait: ReplayWait,
+}
+
+impl Replay
+{
+    pub fn new(commands: Vec<BattleCommand>) -> Replay
+    {
+        Replay{commands, next: 0, wait: ReplayWait::Ready}
+    }
+}
+
+///Sent to restart the battle from the map and play the command log back.
+#[derive(Event)]
+pub struct StartReplay;
+
+///Start a fresh log from the battle's starting rng state. Needs to run after init_game_rng.
+pub fn init_command_log
+(
+    mut cmd: Commands,
+    rng: Res<GameRng>
+)
+{
+    cmd.insert_resource(CommandLog{start: Some(rng.state()), commands: vec![]});
+}
+
+///Log a move whenever a unit starts walking.
+pub fn record_moves
+(
+    mut log: ResMut<CommandLog>,
+    walk_qry: Query<(&UnitID, &WalkPath), Added<WalkPath>>
+)
+{
+    for (unit, walk) in &walk_qry
+    {
+        log.push(BattleCommand::MoveUnit{unit: *unit, to: walk.destination()});
+    }
+}
+
+///Log attacks before they are resolved, while both units still exist.
+pub fn record_attacks
+(
+    mut log: ResMut<CommandLog>,
+    mut attacks: EventReader<AttackUnit>,
+    unit_qry: Query<(&UnitID, &Inventory)>
+)
+{
+    for attack in attacks.read()
+    {
+        let (Ok((unit, inventory)), Ok((target, _))) = (unit_qry.get(attack.attacker), unit_qry.get(attack.defender)) else {continue};
+        log.push(BattleCommand::Attack{unit: *unit, target: *target, weapon: inventory.equipped});
+    }
+}
+
+///Remember where the log is when the player starts moving a unit.
+pub fn mark_rewind_point
+(
+    log: Res<CommandLog>,
+    mut points: ResMut<RewindPoints>
+)
+{
+    if points.0.last() != Some(&log.commands.len())
+    {
+        points.0.push(log.commands.len());
+    }
+}
+
+///A new player phase can't be rewound into the last one.
+pub fn clear_rewind_points(mut points: ResMut<RewindPoints>)
+{
+    points.clear();
+}
+
+///Cut the log back to before the last action of this phase and replay up to there.
+pub fn rewind_turn
+(
+    mut rewind_turn: EventReader<RewindTurn>,
+    mut points: ResMut<RewindPoints>,
+    mut log: ResMut<CommandLog>,
+    mut start_replay: EventWriter<StartReplay>
+)
+{
+    for _event in rewind_turn.read()
+    {
+        //Points where the unit was picked but nothing was done don't count as an action.
+        while let Some(point) = points.0.pop()
+        {
+            if point < log.commands.len()
+            {
+                log.commands.truncate(point);
+                start_replay.send(StartReplay);
+                return;
+            }
+        }
+        println!("Nothing to rewind this phase.");
+    }
+}
+
+///Throw away the battle in progress, rebuild it from the map, and start playing the log back.
+pub fn start_replay
+(
+    mut cmd: Commands,
+    mut start_replay: EventReader<StartReplay>,
+    (map_asset, maps): (Res<MapAsset>, Res<Assets<MapData>>),
+    (class_asset, class_lists): (Res<ClassAsset>, Res<Assets<ClassList>>),
+    (item_asset, item_lists): (Res<ItemAsset>, Res<Assets<ItemList>>),
+    (animation_asset, animation_sets): (Res<AnimationAsset>, Res<Assets<AnimationSetList>>),
+    mut sprite_params: Sprite3dParams,
+    unit_qry: Query<Entity, With<IsUnit>>,
+    mut map_qry: Query<(&mut UnitMap, &mut SelectedUnit)>,
+    mut range: ResMut<MovementRange>,
+    (mut turn, mut fallen): (ResMut<Turn>, ResMut<Fallen>),
+    log: Res<CommandLog>,
+    mut next_phase: ResMut<NextState<Phase>>,
+    mut next_player: ResMut<NextState<Player>>
+)
+{
+    for _event in start_replay.read()
+    {
+        let Some(start) = log.start else
+        {
+            println!("This battle has no record of how it started, so it can't be replayed.");
+            continue;
+        };
+        let (Some(map), Some(classes), Some(items), Some(anim_sets)) = (maps.get(&map_asset.map), class_lists.get(&class_asset.classes), item_lists.get(&item_asset.items), animation_sets.get(&animation_asset.sets)) else {continue};
+        for unit in &unit_qry
+        {
+            cmd.entity(unit).despawn_recursive();
+        }
+        let (mut unit_map, mut selected) = map_qry.single_mut();
+        unit_map.clear();
+        *selected = SelectedUnit::default();
+        *range = MovementRange::default();
+
+        spawn_map_units(&mut cmd, map, classes, anim_sets, items, &mut sprite_params);
+        *turn = Turn::default();
+        fallen.0.clear();
+        cmd.insert_resource(GameRng::restore(start));
+        cmd.insert_resource(Replay::new(log.commands.clone()));
+        next_phase.set(Phase::Player);
+        next_player.set(Player::Field);
+        println!("Replaying {} commands.", log.commands.len());
+    }
+}
+
+///Apply logged commands one at a time, waiting for walks, fights and phase changes to finish in between.
+///
+///Units with sprites walk their logged moves. Without a map or sprites, as in headless runs, they're put straight on the tile.
+pub fn apply_replay
+(
+    mut cmd: Commands,
+    mut replay: ResMut<Replay>,
+    mut unit_qry: Query<(Entity, &UnitID, &mut Location, &mut Inventory)>,
+    walker_qry: Query<(&Movement, &Team, &Class), With<AnimationLibrary>>,
+    map_qry: Query<(&MapSize, &TileMap, &TileList, &UnitMap, &HeightMap)>,
+    team_qry: Query<&Team>,
+    mut walk_finished: EventReader<WalkFinished>,
+    mut combat_finished: EventReader<CombatFinished>,
+    mut attack_unit: EventWriter<AttackUnit>,
+    phase: Option<Res<State<Phase>>>,
+    mut end_phase: EventWriter<EndPhase>,
+    mut rng: ResMut<GameRng>
+)
+{
+    let Some(phase) = phase.map(|phase| phase.get().clone()) else
+    {
+        println!("The battle ended. Replay stopped.");
+        cmd.remove_resource::<Replay>();
+        return;
+    };
+    match &replay.wait
+    {
+        ReplayWait::Combat if combat_finished.read().count() == 0 => return,
+        ReplayWait::Walk(unit) if !walk_finished.read().any(|walked| walked.0 == *unit) => return,
+        ReplayWait::Phase(waiting) if *waiting != phase => return,
+        _ => replay.wait = ReplayWait::Ready,
+    }
+
+    let Some(&command) = replay.commands.get(replay.next) else
+    {
+        println!("Replay finished.");
+        cmd.remove_resource::<Replay>();
+        return;
+    };
+    replay.next += 1;
+    let entities = unit_qry.iter().map(|(entity, unit, ..)| (*unit, entity)).collect::<HashMap<_, _>>();
+    let entity_of = |id: UnitID| entities.get(&id).copied();
+    match command
+    {
+        BattleCommand::MoveUnit{unit, to} =>
+        {
+            let Some((me, _, mut loc, _)) = entity_of(unit).and_then(|me| unit_qry.get_mut(me).ok()) else {return};
+            let path = match (walker_qry.get(me), map_qry.get_single())
+            {
+                (Ok((movement, team, class)), Ok(map)) => path_through(&unit_reachable_tiles(*loc, movement, team, class, map, &team_qry), to),
+                _ => None,
+            };
+            match path
+            {
+                Some(path) if path.len() > 1 =>
+                {
+                    cmd.entity(me).insert(WalkPath::new(path, ClipId::Idle));
+                    replay.wait = ReplayWait::Walk(me);
+                },
+                _ => *loc = to,
+            }
+        },
+        BattleCommand::Equip{unit, slot} =>
+        {
+            if let Some((.., mut inventory)) = entity_of(unit).and_then(|me| unit_qry.get_mut(me).ok())
+            {
+                inventory.equip(slot);
+            }
+        },
+        BattleCommand::Attack{unit, target, weapon} =>
+        {
+            let (Some(attacker), Some(defender)) = (entity_of(unit), entity_of(target)) else
+            {
+                println!("A unit in the logged attack {:?} doesn't exist.", command);
+                return;
+            };
+            if let (Some(slot), Ok((.., mut inventory))) = (weapon, unit_qry.get_mut(attacker))
+            {
+                inventory.equip(slot);
+            }
+            attack_unit.send(AttackUnit{attacker, defender});
+            replay.wait = ReplayWait::Combat;
+        },
+        BattleCommand::Wait{unit} =>
+        {
+            if let Some(me) = entity_of(unit)
+            {
+                cmd.entity(me).insert(Acted);
+            }
+        },
+        BattleCommand::SetHitMode(hit_mode) => rng.set_hit_mode(hit_mode),
+        BattleCommand::EndTurn =>
+        {
+            let next = match phase
+            {
+                Phase::Player => Phase::AI,
+                Phase::AI => Phase::Player,
+            };
+            end_phase.send(EndPhase);
+            replay.wait = ReplayWait::Phase(next);
+        },
+    }
+}
+
+#[cfg(test)]
+mod test
+{
+    use super::*;
+    use bevy::{ecs::system::RunSystemOnce, state::app::StatesPlugin};
+    use crate::level::*;
+    use crate::shared::*;
+    use crate::GameState;
+
+    fn sword() -> WeaponData
+    {
+        WeaponData{name: "Iron Sword".into(), weapon_type: WeaponType::Sword, might: 5, hit: 90, crit: 5, weight: 5, min_range: 1, max_range: 1, uses: 46}
+    }
+
+    ///A battle with no map or sprites, just the systems that decide how fights turn out.
+    fn headless_battle(seed: u64) -> App
+    {
+        let mut app = App::new();
+        app.add_plugins(StatesPlugin)
+            .init_state::<GameState>()
+            .add_sub_state::<Phase>()
+            .add_event::<AttackUnit>()
+            .add_event::<CombatFinished>()
+            .add_event::<GainExp>()
+            .add_event::<EndPhase>()
+            .add_event::<WalkFinished>()
+            .init_resource::<Fallen>()
+            .insert_resource(GameRng::new(seed))
+            .insert_resource(CommandLog{start: Some(RngState{seed, ..default()}), commands: vec![]})
+            .add_systems(Update,
+            (
+                record_moves
+                    .run_if(not(resource_exists::<Replay>)),
+                record_attacks
+                    .before(resolve_attacks)
+                    .run_if(not(resource_exists::<Replay>)),
+                resolve_attacks,
+                gain_exp
+                    .after(resolve_attacks),
+                apply_replay
+                    .before(resolve_attacks)
+                    .run_if(resource_exists::<Replay>),
+                crate::advance_phase
+                    .after(apply_replay),
+            ));
+
+        let class = ClassData
+        {
+            name: "Lord".into(),
+            base: Stats{strength: 6, skill: 7, speed: 8, luck: 6, defense: 5, constitution: 7, ..default()},
+            movement: 5.0,
+            movement_type: MovementType::Foot,
+            terrain_costs: HashMap::new(),
+            animation_set: "Placeholder".into(),
+            growths: Growths{hp: 70, stats: Stats::from_values([50; 8])},
+            caps: Caps::default(),
+        };
+        for (team, loc) in [(PLAYER_TEAM, Location(1, 1)), (1, Location(2, 1))]
+        {
+            let mut inventory = Inventory::default();
+            inventory.add(Item::new(&sword())).unwrap();
+            inventory.add(Item::new(&WeaponData{name: "Slim Sword".into(), might: 3, hit: 100, weight: 2, uses: 30, ..sword()})).unwrap();
+            app.world_mut().spawn(UnitBundle
+            {
+                is_unit: IsUnit,
+                unit_name: ObjName(format!("Unit {}", team)),
+                unit_id: UnitID{team, id: 0},
+                team: Team(team),
+                movement: Movement(5.0),
+                health: Health{max: 40, current: 40, temp: 0},
+                stats: class.base,
+                class: Class(class.clone()),
+                loc,
+                sprite: Sprite("Placeholder".into()),
+                inventory,
+                experience: Experience{level: 9, exp: 95},
+                growths: PersonalGrowths::default(),
+            });
+        }
+        app.update();
+        app
+    }
+
+    fn unit(app: &mut App, team: u32) -> Entity
+    {
+        let mut qry = app.world_mut().query::<(Entity, &UnitID)>();
+        qry.iter(app.world()).find(|(_, id)| id.team == team).unwrap().0
+    }
+
+    fn outcome(app: &mut App) -> Vec<(UnitID, Location, bool, Health, Stats, Experience, Inventory)>
+    {
+        let mut qry = app.world_mut().query::<(&UnitID, &Location, Has<Acted>, &Health, &Stats, &Experience, &Inventory)>();
+        let mut units = qry.iter(app.world()).map(|(id, loc, acted, hp, stats, exp, inv)| (*id, *loc, acted, *hp, *stats, *exp, inv.clone())).collect::<Vec<_>>();
+        units.sort_by_key(|(id, ..)| (id.team, id.id));
+        units
+    }
+
+    #[test]
+    pub fn test_remove_move()
+    {
+        let (alan, martin) = (UnitID{team: 0, id: 1}, UnitID{team: 0, id: 0});
+        let mut log = CommandLog::default();
+        log.push(BattleCommand::MoveUnit{unit: alan, to: Location(3, 3)});
+        log.push(BattleCommand::EndTurn);
+        log.push(BattleCommand::MoveUnit{unit: alan, to: Location(4, 3)});
+        log.push(BattleCommand::MoveUnit{unit: martin, to: Location(1, 1)});
+        log.push(BattleCommand::Equip{unit: martin, slot: 1});
+
+        log.remove_move(alan);
+        assert_eq!(log.commands.len(), 4);
+        assert_eq!(log.commands[2], BattleCommand::MoveUnit{unit: martin, to: Location(1, 1)});
+        //Moves from an earlier phase are already committed.
+        log.remove_move(alan);
+        assert_eq!(log.commands.len(), 4);
+    }
+
+    #[test]
+    pub fn test_rewind_after_load()
+    {
+        let martin = UnitID{team: PLAYER_TEAM, id: 0};
+        let mut app = App::new();
+        app.add_event::<RewindTurn>()
+            .add_event::<StartReplay>()
+            .init_resource::<RewindPoints>()
+            .insert_resource(CommandLog{start: Some(default()), commands: vec![BattleCommand::EndTurn; 4]})
+            .add_systems(Update, rewind_turn);
+        app.world_mut().run_system_once(mark_rewind_point);
+        app.world_mut().resource_mut::<CommandLog>().push(BattleCommand::Wait{unit: martin});
+
+        //Loading swaps in the saved log the same way load_game does.
+        let loaded = vec![BattleCommand::MoveUnit{unit: martin, to: Location(2, 2)}, BattleCommand::Wait{unit: martin}, BattleCommand::EndTurn, BattleCommand::EndTurn, BattleCommand::EndTurn, BattleCommand::EndTurn];
+        app.world_mut().resource_mut::<CommandLog>().commands = loaded.clone();
+        app.world_mut().resource_mut::<RewindPoints>().clear();
+        app.world_mut().send_event(RewindTurn);
+        app.update();
+        assert_eq!(app.world().resource::<CommandLog>().commands, loaded);
+        assert!(app.world().resource::<Events<StartReplay>>().is_empty());
+
+        //Actions after the load can still be taken back.
+        app.world_mut().run_system_once(mark_rewind_point);
+        app.world_mut().resource_mut::<CommandLog>().push(BattleCommand::Wait{unit: martin});
+        app.world_mut().send_event(RewindTurn);
+        app.update();
+        assert_eq!(app.world().resource::<CommandLog>().commands, loaded);
+        assert_eq!(app.world().resource::<Events<StartReplay>>().len(), 1);
+    }
+
+    #[test]
+    pub fn test_replay_reproduces_battle()
+    {
+        let mut live = headless_battle(5);
+        let martin = UnitID{team: PLAYER_TEAM, id: 0};
+        for (i, (attacker, defender)) in [(PLAYER_TEAM, 1), (1, PLAYER_TEAM), (PLAYER_TEAM, 1)].into_iter().enumerate()
+        {
+            //Walk next to the enemy and switch weapons, as the player would.
+            if i == 0
+            {
+                let me = unit(&mut live, PLAYER_TEAM);
+                live.world_mut().entity_mut(me).insert(WalkPath::new(vec![Location(1, 1), Location(1, 2), Location(2, 2)], ClipId::Idle));
+                live.update();
+                //There's no walk_units without a map, so finish the walk by hand.
+                live.world_mut().entity_mut(me).remove::<WalkPath>().insert(Location(2, 2));
+                live.world_mut().get_mut::<Inventory>(me).unwrap().equip(1);
+                live.world_mut().resource_mut::<CommandLog>().push(BattleCommand::Equip{unit: martin, slot: 1});
+            }
+            //Switch to true hit partway through, the way the options menu does.
+            if i == 1
+            {
+                live.world_mut().resource_mut::<GameRng>().set_hit_mode(HitMode::TrueHit);
+                live.world_mut().resource_mut::<CommandLog>().push(BattleCommand::SetHitMode(HitMode::TrueHit));
+            }
+            let (attacker, defender) = (unit(&mut live, attacker), unit(&mut live, defender));
+            live.world_mut().send_event(AttackUnit{attacker, defender});
+            live.update();
+            if i == 2
+            {
+                live.world_mut().entity_mut(attacker).insert(Acted);
+                live.world_mut().resource_mut::<CommandLog>().push(BattleCommand::Wait{unit: martin});
+            }
+            live.world_mut().send_event(EndPhase);
+            live.update();
+        }
+        live.update();
+        assert_eq!(*live.world().resource::<State<Phase>>().get(), Phase::AI);
+        let log = live.world().resource::<CommandLog>().clone();
+        assert_eq!(log.commands.iter().filter(|command| matches!(command, BattleCommand::Attack{..})).count(), 3);
+        assert_eq!(log.commands.iter().filter(|command| **command == BattleCommand::EndTurn).count(), 3);
+        assert_eq!(log.commands[0], BattleCommand::MoveUnit{unit: martin, to: Location(2, 2)});
+
+        let mut replayed = headless_battle(log.start.unwrap().seed);
+        replayed.insert_resource(Replay::new(log.commands.clone()));
+        for _ in 0..20
+        {
+            replayed.update();
+        }
+        assert!(!replayed.world().contains_resource::<Replay>());
+        assert_eq!(outcome(&mut replayed), outcome(&mut live));
+        let (_, loc, acted, .., inventory) = outcome(&mut replayed).remove(0);
+        assert_eq!((loc, acted, inventory.equipped), (Location(2, 2), true, Some(1)));
+        assert_eq!(replayed.world().resource::<GameRng>().state(), live.world().resource::<GameRng>().state());
+        assert_eq!(replayed.world().resource::<GameRng>().hit_mode(), HitMode::TrueHit);
+        assert_eq!(replayed.world().resource::<CommandLog>().commands, vec![]);
+    }
+}
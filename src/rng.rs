@@ -0,0 +1,160 @@
+use bevy::prelude::*;
+use rand::{Rng, RngCore, SeedableRng};
+use rand_chacha::ChaCha8Rng;
+use serde::{Deserialize, Serialize};
+
+/// How hit chances are rolled.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum HitMode
+{
+    ///One roll against the hit chance.
+    #[default] Single,
+    ///Average of two rolls against the hit chance. High hit chances land more often and low ones less.
+    TrueHit,
+}
+
+/// Something that rolls percentages for gameplay.
+pub trait Roll
+{
+    ///A number in 0..100.
+    fn roll(&mut self) -> u32;
+
+    ///Whether an attack with `chance` percent to hit lands.
+    fn roll_hit(&mut self, chance: u32) -> bool
+    {
+        self.roll() < chance
+    }
+}
+
+impl<R: Rng> Roll for R
+{
+    fn roll(&mut self) -> u32
+    {
+        self.gen_range(0..100)
+    }
+}
+
+/// Where a GameRng is in its sequence. Enough to rebuild it exactly.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct RngState
+{
+    pub seed: u64,
+    ///How many rolls have been drawn since seeding.
+    pub draws: u64,
+    #[serde(default)]
+    pub hit_mode: HitMode,
+}
+
+/// The one source of randomness for gameplay.
+///
+/// Every roll goes through here in a fixed order so a battle can be replayed from its seed:
+/// each strike rolls hit and then, only if it hit, crit. A level-up rolls HP and then each stat in `Stats::NAMES` order.
+///
+/// ChaCha8 and the roll below are fixed algorithms, so saved seeds give the same rolls on every platform and rand version.
+#[derive(Resource)]
+pub struct GameRng
+{
+    rng: ChaCha8Rng,
+    state: RngState,
+}
+
+impl GameRng
+{
+    pub fn new(seed: u64) -> GameRng
+    {
+        GameRng::restore(RngState{seed, ..default()})
+    }
+
+    ///Reseed and skip ahead to where `state` left off.
+    pub fn restore(state: RngState) -> GameRng
+    {
+        let mut rng = GameRng{rng: ChaCha8Rng::seed_from_u64(state.seed), state: RngState{draws: 0, ..state}};
+        for _ in 0..state.draws
+        {
+            rng.roll();
+        }
+        rng
+    }
+
+    pub fn state(&self) -> RngState
+    {
+        self.state
+    }
+
+    pub fn hit_mode(&self) -> HitMode
+    {
+        self.state.hit_mode
+    }
+
+    pub fn set_hit_mode(&mut self, hit_mode: HitMode)
+    {
+        self.state.hit_mode = hit_mode;
+    }
+}
+
+impl Roll for GameRng
+{
+    fn roll(&mut self) -> u32
+    {
+        self.state.draws += 1;
+        //Scale a 32 bit draw down to 0..100 by hand rather than rely on how rand samples ranges.
+        ((self.rng.next_u32() as u64 * 100) >> 32) as u32
+    }
+
+    fn roll_hit(&mut self, chance: u32) -> bool
+    {
+        match self.state.hit_mode
+        {
+            HitMode::Single => self.roll() < chance,
+            HitMode::TrueHit => (self.roll() + self.roll()) / 2 < chance,
+        }
+    }
+}
+
+///Seed the gameplay rng for a new battle.
+pub fn init_game_rng(mut cmd: Commands)
+{
+    let seed = rand::random();
+    println!("Battle seed: {}", seed);
+    cmd.insert_resource(GameRng::new(seed));
+}
+
+#[cfg(test)]
+mod test
+{
+    use super::*;
+
+    #[test]
+    pub fn test_restore_continues_sequence()
+    {
+        let mut rng = GameRng::new(42);
+        for _ in 0..7
+        {
+            rng.roll();
+        }
+        let state = rng.state();
+        assert_eq!(state.draws, 7);
+        let mut restored = GameRng::restore(state);
+        let ahead = (0..10).map(|_| rng.roll()).collect::<Vec<_>>();
+        assert_eq!((0..10).map(|_| restored.roll()).collect::<Vec<_>>(), ahead);
+        assert_eq!(restored.state(), rng.state());
+    }
+
+    #[test]
+    pub fn test_true_hit_draws_twice()
+    {
+        let mut rng = GameRng::new(1);
+        rng.set_hit_mode(HitMode::TrueHit);
+        assert!(rng.roll_hit(100));
+        assert!(!rng.roll_hit(0));
+        assert_eq!(rng.state().draws, 4);
+    }
+
+    #[test]
+    pub fn test_rolls_are_stable()
+    {
+        //Saves and replays depend on these never changing.
+        let mut rng = GameRng::new(42);
+        assert_eq!((0..8).map(|_| rng.roll()).collect::<Vec<_>>(), vec![22, 68, 14, 95, 77, 42, 34, 62]);
+    }
+}
@@ -0,0 +1,386 @@
+use std::{fmt, fs, path::PathBuf};
+
+use bevy::prelude::*;
+use serde::{Deserialize, Serialize};
+use bevy_sprite3d::*;
+
+use crate::animation::*;
+use crate::class::*;
+use crate::item::*;
+use crate::level::*;
+use crate::map::*;
+use crate::map_data::*;
+use crate::objective::*;
+use crate::pathing::*;
+use crate::replay::*;
+use crate::rng::*;
+use crate::shared::*;
+use crate::turn::*;
+use crate::unit::{Sprite, *};
+use crate::{Phase, Player};
+
+///Folder under the platform data directory that saves are kept in.
+const SAVE_FOLDER: &str = "my_game/saves";
+
+/// Where a save goes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SaveSlot
+{
+    Slot(u8),
+    ///Quick save that is meant to be resumed once.
+    Suspend,
+}
+
+impl SaveSlot
+{
+    pub fn file_name(&self) -> String
+    {
+        match self
+        {
+            SaveSlot::Slot(n) => format!("slot_{}.ron", n),
+            SaveSlot::Suspend => "suspend.ron".into(),
+        }
+    }
+
+    pub fn path(&self) -> Option<PathBuf>
+    {
+        Some(dirs::data_dir()?.join(SAVE_FOLDER).join(self.file_name()))
+    }
+}
+
+/// Everything needed to rebuild a single unit.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct UnitSave
+{
+    pub name: String,
+    pub team: u32,
+    ///Order of the unit within its team, see `UnitID`.
+    pub id: u32,
+    pub class: String,
+    pub stats: Stats,
+    pub movement: f32,
+    pub health: Health,
+    pub location: Location,
+    pub sprite: String,
+    pub acted: bool,
+    ///Name and remaining uses of each carried item.
+    #[serde(default)]
+    pub items: Vec<(String, u32)>,
+    #[serde(default)]
+    pub equipped: Option<usize>,
+    #[serde(default)]
+    pub experience: Experience,
+    ///Personal growths, see `PersonalGrowths`.
+    #[serde(default)]
+    pub growths: Growths,
+    #[serde(default)]
+    pub lord: bool,
+    #[serde(default)]
+    pub boss: bool,
+}
+
+/// A whole battle in progress.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct SaveData
+{
+    ///Asset path of the map the battle is on.
+    pub map: String,
+    pub turn: u32,
+    pub phase: Phase,
+    pub units: Vec<UnitSave>,
+    #[serde(default)]
+    pub fallen: Vec<FallenUnit>,
+    ///Where the gameplay rng was, so loading doesn't change upcoming rolls.
+    #[serde(default)]
+    pub rng: RngState,
+    ///Everything that happened before the save, so the battle can still be replayed after loading.
+    #[serde(default)]
+    pub log: CommandLog,
+}
+
+#[derive(Debug)]
+pub enum SaveError
+{
+    NoDataDir,
+    Io(std::io::Error),
+    Write(ron::Error),
+    Read(ron::error::SpannedError),
+}
+
+impl fmt::Display for SaveError
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result
+    {
+        match self
+        {
+            SaveError::NoDataDir => write!(f, "couldn't find a data directory to save to"),
+            SaveError::Io(err) => write!(f, "couldn't access save file: {}", err),
+            SaveError::Write(err) => write!(f, "couldn't write save: {}", err),
+            SaveError::Read(err) => write!(f, "couldn't read save: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for SaveError {}
+
+impl From<std::io::Error> for SaveError
+{
+    fn from(err: std::io::Error) -> Self
+    {
+        SaveError::Io(err)
+    }
+}
+
+impl SaveData
+{
+    pub fn to_ron(&self) -> Result<String, SaveError>
+    {
+        ron::ser::to_string_pretty(self, ron::ser::PrettyConfig::default()).map_err(SaveError::Write)
+    }
+
+    pub fn from_ron(text: &str) -> Result<SaveData, SaveError>
+    {
+        ron::de::from_str(text).map_err(SaveError::Read)
+    }
+
+    pub fn write(&self, slot: SaveSlot) -> Result<PathBuf, SaveError>
+    {
+        let path = slot.path().ok_or(SaveError::NoDataDir)?;
+        if let Some(dir) = path.parent()
+        {
+            fs::create_dir_all(dir)?;
+        }
+        fs::write(&path, self.to_ron()?)?;
+        Ok(path)
+    }
+
+    pub fn read(slot: SaveSlot) -> Result<SaveData, SaveError>
+    {
+        let path = slot.path().ok_or(SaveError::NoDataDir)?;
+        SaveData::from_ron(&fs::read_to_string(path)?)
+    }
+}
+
+#[derive(Event)]
+pub struct SaveGame(pub SaveSlot);
+
+#[derive(Event)]
+pub struct LoadGame(pub SaveSlot);
+
+///Write the current battle to the requested slots.
+pub fn save_game
+(
+    mut save_game: EventReader<SaveGame>,
+    map_asset: Res<MapAsset>,
+    turn: Res<Turn>,
+    phase: Res<State<Phase>>,
+    unit_qry: Query<(&ObjName, &UnitID, &Team, &Class, &Stats, &Movement, &Health, &Location, &Sprite, &Inventory, (&Experience, &PersonalGrowths), Has<Acted>, Has<Lord>, Has<Boss>), With<IsUnit>>,
+    fallen: Res<Fallen>,
+    rng: Res<GameRng>,
+    log: Res<CommandLog>
+)
+{
+    for event in save_game.read()
+    {
+        let mut units = unit_qry.iter()
+            .map(|(name, unit_id, team, class, stats, movement, health, loc, sprite, inventory, (experience, growths), acted, lord, boss)| UnitSave
+            {
+                name: name.0.clone(),
+                team: team.0,
+                id: unit_id.id,
+                class: class.0.name.clone(),
+                stats: *stats,
+                movement: movement.0,
+                health: *health,
+                location: *loc,
+                sprite: sprite.0.clone(),
+                acted,
+                items: inventory.items.iter().map(|item| (item.weapon.name.clone(), item.uses)).collect(),
+                equipped: inventory.equipped,
+                experience: *experience,
+                growths: growths.0,
+                lord,
+                boss,
+            })
+            .collect::<Vec<_>>();
+        //Keep the file stable between saves of the same battle.
+        units.sort_by_key(|unit| (unit.team, unit.id));
+
+        let data = SaveData
+        {
+            map: map_asset.path.clone(),
+            turn: turn.0,
+            phase: phase.get().clone(),
+            units,
+            fallen: fallen.0.clone(),
+            rng: rng.state(),
+            log: log.clone(),
+        };
+        match data.write(event.0)
+        {
+            Ok(path) => println!("Saved to {}", path.display()),
+            Err(err) => println!("Save failed: {}", err),
+        }
+    }
+}
+
+///Throw away every unit and rebuild the battle from a save.
+pub fn load_game
+(
+    mut cmd: Commands,
+    mut load_game: EventReader<LoadGame>,
+    map_asset: Res<MapAsset>,
+    class_asset: Res<ClassAsset>,
+    class_lists: Res<Assets<ClassList>>,
+    (item_asset, item_lists): (Res<ItemAsset>, Res<Assets<ItemList>>),
+    animation_asset: Res<AnimationAsset>,
+    animation_sets: Res<Assets<AnimationSetList>>,
+    mut sprite_params: Sprite3dParams,
+    unit_qry: Query<Entity, With<IsUnit>>,
+    mut map_qry: Query<(&mut UnitMap, &mut SelectedUnit)>,
+    mut range: ResMut<MovementRange>,
+    (mut turn, mut fallen, mut rewind_points): (ResMut<Turn>, ResMut<Fallen>, ResMut<RewindPoints>),
+    mut next_phase: ResMut<NextState<Phase>>,
+    mut next_player: ResMut<NextState<Player>>
+)
+{
+    for event in load_game.read()
+    {
+        let data = match SaveData::read(event.0)
+        {
+            Ok(data) => data,
+            Err(err) =>
+            {
+                println!("Load failed: {}", err);
+                continue;
+            },
+        };
+        if data.map != map_asset.path
+        {
+            println!("Load failed: save is for map {} but {} is loaded.", data.map, map_asset.path);
+            continue;
+        }
+        let Some(classes) = class_lists.get(&class_asset.classes) else {continue};
+        let Some(anim_sets) = animation_sets.get(&animation_asset.sets) else {continue};
+        let Some(items) = item_lists.get(&item_asset.items) else {continue};
+        if let Some(missing) = data.units.iter().find(|unit| classes.get(&unit.class).is_none())
+        {
+            println!("Load failed: class {} of {} doesn't exist.", missing.class, missing.name);
+            continue;
+        }
+        if let Some(missing) = data.units.iter().find(|unit| anim_sets.get(&unit.sprite).is_none())
+        {
+            println!("Load failed: animation set {} of {} doesn't exist.", missing.sprite, missing.name);
+            continue;
+        }
+
+        if let Some((unit, (item, _))) = data.units.iter().find_map(|unit| Some((unit, unit.items.iter().find(|(item, _)| items.get(item).is_none())?)))
+        {
+            println!("Load failed: item {} of {} doesn't exist.", item, unit.name);
+            continue;
+        }
+
+        for unit in &unit_qry
+        {
+            cmd.entity(unit).despawn_recursive();
+        }
+        let (mut unit_map, mut selected) = map_qry.single_mut();
+        unit_map.clear();
+        *selected = SelectedUnit::default();
+        *range = MovementRange::default();
+
+        for unit in &data.units
+        {
+            let class = classes.get(&unit.class).unwrap();
+            let anim_set = anim_sets.get(&unit.sprite).unwrap();
+            let inventory = Inventory
+            {
+                items: unit.items.iter().map(|(name, uses)| Item{weapon: items.get(name).unwrap().clone(), uses: *uses}).collect(),
+                equipped: unit.equipped.filter(|i| *i < unit.items.len()),
+            };
+            let me = spawn_unit(&mut cmd, anim_set, &mut sprite_params, UnitBundle
+            {
+                is_unit: IsUnit,
+                unit_name: ObjName(unit.name.clone()),
+                unit_id: UnitID{team: unit.team, id: unit.id},
+                team: Team(unit.team),
+                movement: Movement(unit.movement),
+                health: unit.health,
+                stats: unit.stats,
+                class: Class(class.clone()),
+                loc: unit.location,
+                sprite: Sprite(unit.sprite.clone()),
+                inventory,
+                experience: unit.experience,
+                growths: PersonalGrowths(unit.growths),
+            });
+            if unit.acted
+            {
+                cmd.entity(me).insert(Acted);
+            }
+            if unit.lord
+            {
+                cmd.entity(me).insert(Lord);
+            }
+            if unit.boss
+            {
+                cmd.entity(me).insert(Boss);
+            }
+        }
+        turn.0 = data.turn;
+        fallen.0 = data.fallen.clone();
+        cmd.insert_resource(GameRng::restore(data.rng));
+        cmd.insert_resource(data.log.clone());
+        cmd.remove_resource::<Replay>();
+        //Points into the old log mean nothing in the loaded one.
+        rewind_points.clear();
+        next_phase.set(data.phase.clone());
+        next_player.set(Player::Field);
+        println!("Loaded turn {} with {} units.", data.turn, data.units.len());
+    }
+}
+
+#[cfg(test)]
+mod test
+{
+    use super::*;
+
+    #[test]
+    pub fn test_save_round_trip()
+    {
+        let data = SaveData
+        {
+            map: "maps/test.map.ron".into(),
+            turn: 3,
+            phase: Phase::Player,
+            units: vec![UnitSave
+            {
+                name: "Martin".into(),
+                team: 0,
+                id: 0,
+                class: "Lord".into(),
+                stats: Stats{strength: 6, speed: 8, ..default()},
+                movement: 5.0,
+                health: Health{max: 20, current: 13, temp: 2},
+                location: Location(7, 9),
+                sprite: "Placeholder".into(),
+                acted: true,
+                items: vec![("Iron Sword".into(), 12)],
+                equipped: Some(0),
+                experience: Experience{level: 3, exp: 42},
+                growths: Growths{hp: 20, stats: Stats{speed: 10, ..default()}},
+                lord: true,
+                boss: false,
+            }],
+            fallen: vec![FallenUnit{name: "Alan".into(), team: 0, lord: false}],
+            rng: RngState{seed: 99, draws: 14, hit_mode: HitMode::TrueHit},
+            log: CommandLog
+            {
+                start: Some(RngState{seed: 99, ..default()}),
+                commands: vec![BattleCommand::MoveUnit{unit: UnitID{team: 0, id: 0}, to: Location(7, 8)}, BattleCommand::EndTurn],
+            },
+        };
+        assert_eq!(SaveData::from_ron(&data.to_ron().unwrap()).unwrap(), data);
+        assert_eq!(SaveSlot::Slot(2).file_name(), "slot_2.ron");
+    }
+}
@@ -7,6 +7,8 @@ use bevy::{
     window::{CursorGrabMode, PresentMode, PrimaryWindow, WindowLevel, WindowMode, WindowTheme},
 };
 
+use crate::unit::Location;
+
 macro_rules! pubify {
     {
         $(#[derive($($derive:ident),*)])*
@@ -34,7 +36,9 @@ pub(crate) use pubify;
 pub struct SelectorLocation
 {
     pub precise_location: Vec3,
-    pub tile_location: Vec3
+    pub tile_location: Vec3,
+    ///The map tile under the selector, or None when it's off the map.
+    pub tile: Option<Location>,
 }
 
 pubify!(#[derive(Component)]
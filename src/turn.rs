@@ -0,0 +1,180 @@
+use bevy::prelude::*;
+
+use crate::combat::*;
+use crate::input::*;
+use crate::map::*;
+use crate::shared::*;
+use crate::unit::*;
+use crate::Phase;
+
+///Color multiplied into a unit's sprite once it has acted.
+const ACTED_TINT: Color = Color::srgb(0.35, 0.35, 0.35);
+
+/// The current turn. A turn is one player phase followed by one AI phase.
+#[derive(Resource)]
+pub struct Turn(pub u32);
+
+impl Default for Turn
+{
+    fn default() -> Self
+    {
+        Turn(1)
+    }
+}
+
+/// Marks a unit that has already acted or waited this phase.
+#[derive(Component)]
+pub struct Acted;
+
+/// The material a unit had before it was greyed out for acting.
+#[derive(Component)]
+pub struct PreActedMaterial(Handle<StandardMaterial>);
+
+///Only player units that haven't acted yet can be picked during the player phase.
+pub fn can_select(team: &Team, acted: bool) -> bool
+{
+    team.0 == PLAYER_TEAM && !acted
+}
+
+///Clear every unit's acted flag so the new phase starts fresh.
+pub fn reset_acted
+(
+    mut cmd: Commands,
+    acted_qry: Query<Entity, With<Acted>>
+)
+{
+    for unit in &acted_qry
+    {
+        cmd.entity(unit).remove::<Acted>();
+    }
+}
+
+///Health restored by a tile that heals `heal` percent of max health. Healing tiles always restore at least 1.
+pub fn terrain_heal_amount(max: u32, heal: u32) -> u32
+{
+    if heal == 0
+    {
+        return 0;
+    }
+    (max * heal / 100).max(1)
+}
+
+///Heal the units of the team whose phase is starting that stand on healing tiles.
+pub fn terrain_heal
+(
+    phase: Res<State<Phase>>,
+    map_qry: Query<(&TileMap, &TileList)>,
+    mut unit_qry: Query<(&ObjName, &Team, &Location, &mut Health)>
+)
+{
+    let Ok((tile_map, tile_list)) = map_qry.get_single() else {return};
+    let player_phase = *phase.get() == Phase::Player;
+    for (name, team, loc, mut health) in unit_qry.iter_mut()
+    {
+        if (team.0 == PLAYER_TEAM) != player_phase
+        {
+            continue;
+        }
+        let tile = &tile_list.0[&tile_map.0[loc.1][loc.0]];
+        let amount = terrain_heal_amount(health.max, tile.heal);
+        let healed = health.heal(amount);
+        if healed > 0
+        {
+            println!("{} heals {} on the {}.", name.0, healed, tile.name);
+        }
+    }
+}
+
+pub fn advance_turn(mut turn: ResMut<Turn>)
+{
+    turn.0 += 1;
+    println!("Turn {}", turn.0);
+}
+
+///A player unit is done once it has fought. Waiting is handled by the action menu.
+pub fn mark_player_acted
+(
+    mut cmd: Commands,
+    mut combat_finished: EventReader<CombatFinished>,
+    team_qry: Query<&Team>
+)
+{
+    for unit in combat_finished.read().map(|event| event.attacker)
+    {
+        if team_qry.get(unit).is_ok_and(|team| team.0 == PLAYER_TEAM)
+        {
+            if let Some(mut unit_cmd) = cmd.get_entity(unit)
+            {
+                unit_cmd.insert(Acted);
+            }
+        }
+    }
+}
+
+///Hand the turn to the AI once every player unit has acted.
+pub fn check_player_phase_done
+(
+    unit_qry: Query<(&Team, Has<Acted>), With<IsUnit>>,
+    mut end_phase: EventWriter<EndPhase>
+)
+{
+    let mut player_units = unit_qry.iter().filter(|(team, _)| team.0 == PLAYER_TEAM).peekable();
+    if player_units.peek().is_some() && player_units.all(|(_, acted)| acted)
+    {
+        end_phase.send(EndPhase);
+    }
+}
+
+///Swap units that just acted over to a darker copy of their material.
+pub fn grey_out_acted
+(
+    mut cmd: Commands,
+    mut unit_qry: Query<(Entity, &mut Handle<StandardMaterial>), Added<Acted>>,
+    mut materials: ResMut<Assets<StandardMaterial>>
+)
+{
+    for (unit, mut material) in unit_qry.iter_mut()
+    {
+        let Some(mut grey) = materials.get(material.id()).cloned() else {continue};
+        let (color, tint) = (grey.base_color.to_linear(), ACTED_TINT.to_linear());
+        grey.base_color = LinearRgba::new(color.red * tint.red, color.green * tint.green, color.blue * tint.blue, color.alpha).into();
+        cmd.entity(unit).insert(PreActedMaterial(material.clone()));
+        *material = materials.add(grey);
+    }
+}
+
+///Give units their own material back once their acted flag is cleared.
+pub fn restore_acted_color
+(
+    mut cmd: Commands,
+    mut removed: RemovedComponents<Acted>,
+    mut unit_qry: Query<(&mut Handle<StandardMaterial>, &PreActedMaterial)>
+)
+{
+    for unit in removed.read()
+    {
+        if let Ok((mut material, original)) = unit_qry.get_mut(unit)
+        {
+            *material = original.0.clone();
+            cmd.entity(unit).remove::<PreActedMaterial>();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test
+{
+    use super::*;
+
+    #[test]
+    pub fn test_terrain_heal()
+    {
+        assert_eq!(terrain_heal_amount(20, 10), 2);
+        assert_eq!(terrain_heal_amount(8, 10), 1);
+        assert_eq!(terrain_heal_amount(20, 0), 0);
+
+        let mut health = Health{max: 20, current: 19, temp: 0};
+        assert_eq!(health.heal(terrain_heal_amount(health.max, 10)), 1);
+        assert_eq!(health.current, 20);
+    }
+}
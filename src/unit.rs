@@ -1,4 +1,4 @@
-use std::{f32::consts::PI, time::Duration};
+use std::{collections::VecDeque, f32::consts::PI, time::Duration};
 
 use bevy::{
     ecs::{schedule::SystemSetConfig, system::EntityCommands},
@@ -10,11 +10,18 @@ use bevy::{
 };
 
 use bevy_sprite3d::*;
+use serde::{Deserialize, Serialize};
 
 use crate::shared::*;
 use crate::map::*;
-
-pubify!(#[derive(Component)]
+use crate::map_data::*;
+use crate::class::*;
+use crate::animation::*;
+use crate::objective::*;
+use crate::item::*;
+use crate::level::*;
+
+pubify!(#[derive(Component, Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
 struct Health
 {
     max: u32,
@@ -22,6 +29,32 @@ struct Health
     temp: u32,
 });
 
+impl Health
+{
+    ///Apply damage, using up temporary health before current health. Returns the damage actually taken.
+    pub fn take_damage(&mut self, damage: u32) -> u32
+    {
+        let from_temp = damage.min(self.temp);
+        self.temp -= from_temp;
+        let from_current = (damage - from_temp).min(self.current);
+        self.current -= from_current;
+        from_temp + from_current
+    }
+
+    ///Restore health up to max. Returns the amount actually healed.
+    pub fn heal(&mut self, amount: u32) -> u32
+    {
+        let healed = amount.min(self.max.saturating_sub(self.current));
+        self.current += healed;
+        healed
+    }
+
+    pub fn is_dead(&self) -> bool
+    {
+        self.current == 0
+    }
+}
+
 impl Default for Health {
     fn default() -> Self 
     { Health
@@ -34,15 +67,35 @@ impl Default for Health {
 }
 
 /// A location on the map grid.
-#[derive(Component, Clone, Copy)]
+#[derive(Component, Clone, Copy, PartialEq, Eq, Hash, Debug, Serialize, Deserialize)]
 pub struct Location(pub usize, pub usize);
 
+impl Location
+{
+    ///The location at signed grid coordinates, or None if they fall outside of the map.
+    pub fn from_signed(x: isize, z: isize, map_size: &MapSize) -> Option<Location>
+    {
+        if x < 0 || z < 0 || x as usize >= map_size.0 || z as usize >= map_size.1
+        {
+            return None;
+        }
+        Some(Location(x as usize, z as usize))
+    }
+
+    ///The tile a world position is over. Tile centers sit on whole numbers, so each tile reaches half a unit either way.
+    pub fn from_world(pos: Vec3, map_size: &MapSize) -> Option<Location>
+    {
+        Location::from_signed(pos.x.round() as isize, pos.z.round() as isize, map_size)
+    }
+}
+
 
-#[derive(Component, PartialEq, Debug, Clone)]
+/// Identifies a unit by its team and its order within that team.
+#[derive(Component, PartialEq, Eq, Hash, Debug, Clone, Copy, Serialize, Deserialize)]
 pub struct UnitID
 {
-    team: u32,
-    id: u32
+    pub team: u32,
+    pub id: u32
 }
 
 impl Default for UnitID
@@ -56,12 +109,30 @@ impl Default for UnitID
     }
 }
 
+///The team the player controls. Every other team is run by the AI.
+pub const PLAYER_TEAM: u32 = 0;
+
+///Tint applied to each team's sprites, indexed by team number.
+const TEAM_COLORS: [Color; 4] =
+[
+    Color::srgb(0.7, 0.85, 1.0),
+    Color::srgb(1.0, 0.6, 0.6),
+    Color::srgb(0.7, 1.0, 0.7),
+    Color::srgb(1.0, 0.95, 0.6),
+];
+
+pub fn team_color(team: u32) -> Color
+{
+    TEAM_COLORS[team as usize % TEAM_COLORS.len()]
+}
+
 #[derive(Component)]
 pub struct Team(pub u32);
 
 #[derive(Component)]
 pub struct Movement(pub f32);
 
+///Name of the animation set the unit is drawn with.
 #[derive(Component)]
 pub struct Sprite(pub String);
 
@@ -77,124 +148,104 @@ pub struct TestTimer
     timer: Timer
 }
 
-// Note: To speed loading this up, I can change "String" to &str, which will make it static. Remember this if I want the extra speed later.
-// Todo: Alternatively, just make it generic for type saftey and maximum speed. Not really any reason it can't just be an enum.
-// May require deserialization.
-/// A library of animations.
+pubify!(#[derive(Bundle)]
+struct UnitBundle
+{
+    is_unit: IsUnit,
+    unit_name: ObjName,
+    unit_id: UnitID,
+    team: Team,
+    movement: Movement,
+    health: Health,
+    stats: Stats,
+    class: Class,
+    loc: Location,
+    sprite: Sprite,
+    inventory: Inventory,
+    experience: Experience,
+    growths: PersonalGrowths,
+    //model: PbrBundle,
+});
+
+#[derive(Event)]
+pub struct UpdateUnitRenderLocation(Entity);
+
+///Sent when a unit reaches the end of its WalkPath.
+#[derive(Event)]
+pub struct WalkFinished(pub Entity);
+
+///How many tiles a unit crosses per second while walking a path.
+const WALK_SPEED: f32 = 5.0;
+
+/// A path the unit is currently walking, one tile at a time.
+///
+/// The unit's `Location` is only updated once the last tile is reached.
 #[derive(Component)]
-pub struct AnimationLibrary 
+pub struct WalkPath
 {
-    animations: HashMap<String, Vec<usize>>,
-    current_animation: String,
-    //animations: Vec<usize>,
-    current_frame: usize,
-    timer: Timer,
+    steps: VecDeque<Location>,
+    from: Location,
+    progress: f32,
+    resume_animation: ClipId,
 }
 
-impl AnimationLibrary
+impl WalkPath
 {
-    fn builder() -> AnimationLibraryBuilder
-    { 
-        AnimationLibraryBuilder::default()
+    ///The tile the unit ends up on.
+    pub fn destination(&self) -> Location
+    {
+        self.steps.back().copied().unwrap_or(self.from)
     }
 
-    pub fn set_animation(&mut self, animation: String)
+    ///`path` should start at the unit's current location, as returned by `MovementRange::path_to`.
+    pub fn new(path: Vec<Location>, resume_animation: ClipId) -> WalkPath
     {
-        self.current_animation = animation;
+        let mut steps: VecDeque<Location> = path.into();
+        let from = steps.pop_front().expect("Can't walk an empty path!");
+        WalkPath
+        {
+            steps,
+            from,
+            progress: 0.0,
+            resume_animation,
+        }
     }
 }
 
-#[derive(Default)]
-pub struct AnimationLibraryBuilder
+///Where a unit standing on the given tile is drawn.
+pub fn unit_translation(loc: Location, height: u32) -> Vec3
 {
-    animations: HashMap<String, Vec<usize>>,
-    current_animation: String,
-    current_frame: usize,
-    timer: Timer
+    Vec3::new(loc.0 as f32, tile_surface(height) + 0.5, loc.1 as f32)
 }
 
-impl AnimationLibraryBuilder
+///Pick the running animation that matches a single step.
+fn step_animation(from: Location, to: Location) -> ClipId
 {
-    pub fn new() -> AnimationLibraryBuilder
+    if to.1 > from.1
     {
-        let mut me = AnimationLibraryBuilder
-        {
-            animations: HashMap::new(),
-            current_animation: "idle".into(),
-            current_frame: 0,
-            timer: Timer::new(Duration::from_millis(200), TimerMode::Repeating),
-        };
-        me.animations.insert("idle".into(), (0..1).collect());
-        return me
-    }
-    ///Pass a hashmap of animations to this to fill the animation library automatically.
-    pub fn set_animations(mut self, hash_map: HashMap<String, Vec<usize>>) -> AnimationLibraryBuilder
+        ClipId::RunningDown
+    } else if to.1 < from.1
     {
-        for (name, frames) in hash_map
-        {
-            self.animations.insert(name.clone(), frames.clone());
-        };
-        self
-    }
-    ///Set the current animation by name.
-    pub fn set_animation(mut self, name: String) -> AnimationLibraryBuilder
-    {
-        self.current_animation = name;
-        self
-    }
-    ///Set the frame of the current animation, relative to the animation itself, not the index of the complete sprite sheet.
-    pub fn set_frame(mut self, frame: usize) -> AnimationLibraryBuilder
+        ClipId::RunningUp
+    } else
     {
-        self.current_frame = frame;
-        self
-    }
-    ///Set the timer in milliseconds.
-    pub fn set_timer(mut self, millsec: u64) -> AnimationLibraryBuilder
-    {
-        self.timer = Timer::new(Duration::from_millis(millsec), TimerMode::Repeating);
-        self
-    }
-    ///Set the timer in frames per second.
-    pub fn set_frames_per(mut self, frames_per: u16) -> AnimationLibraryBuilder
-    {
-        let convert = 1000/frames_per;
-        self.timer = Timer::new(Duration::from_millis(convert.into()), TimerMode::Repeating);
-        self
-    }
-    pub fn build(self) -> AnimationLibrary
-    {
-        AnimationLibrary
-        {
-            animations: self.animations,
-            current_animation: self.current_animation,
-            current_frame: self.current_frame,
-            timer: self.timer,
-        }
+        ClipId::RunningLr
     }
 }
 
-pubify!(#[derive(Bundle)]
-struct UnitBundle
-{
-    is_unit: IsUnit,
-    unit_name: ObjName,
-    team: Team,
-    movement: Movement,
-    health: Health,
-    loc: Location,
-    sprite: Sprite,
-    //model: PbrBundle,
-});
-
-#[derive(Event)]
-pub struct UpdateUnitRenderLocation(Entity);
-
-///Hello!
-#[derive(Resource, Default, Clone)]
-pub struct ImageAsset
+///Give newly spawned units their own copy of the sprite material, tinted with their team's color.
+pub fn tint_team_colors
+(
+    mut unit_qry: Query<(&Team, &mut Handle<StandardMaterial>), Added<Team>>,
+    mut materials: ResMut<Assets<StandardMaterial>>
+)
 {
-    pub image: Handle<Image>,
-    pub layout: Handle<TextureAtlasLayout>
+    for (team, mut material) in unit_qry.iter_mut()
+    {
+        let Some(mut tinted) = materials.get(&*material).cloned() else {continue};
+        tinted.base_color = team_color(team.0);
+        *material = materials.add(tinted);
+    }
 }
 
 pub fn face_camera
@@ -218,99 +269,143 @@ pub fn face_camera
     }
 }
 
-///Load in unit sprite. Needs to run before init_unit_model.
-pub fn init_unit_sprite
+///Spawn every unit the map declares.
+pub fn init_unit_model
 (
-    mut cmd: Commands,
-    mut layouts: ResMut<Assets<TextureAtlasLayout>>,
-    asset_server: Res<AssetServer>
+    mut cmd: Commands, 
+    animation_asset: Res<AnimationAsset>,
+    animation_sets: Res<Assets<AnimationSetList>>,
+    mut update_unit_render_location: EventWriter<UpdateUnitRenderLocation>,
+    mut sprite_params: Sprite3dParams,
+    map_asset: Res<MapAsset>,
+    maps: Res<Assets<MapData>>,
+    class_asset: Res<ClassAsset>,
+    class_lists: Res<Assets<ClassList>>,
+    item_asset: Res<ItemAsset>,
+    item_lists: Res<Assets<ItemList>>,
 )
 {
-    let pic: Handle<Image> = asset_server.load("PlaceholderMSprite.png");
-    let layout: Handle<TextureAtlasLayout> = layouts.add
-    (TextureAtlasLayout::from_grid
-        (
-            UVec2::new(32, 32), 
-            4, 
-            5,
-            None, 
-            None
-        )
-    );
-    cmd.insert_resource(ImageAsset{image: pic, layout});
+    let (Some(map), Some(classes), Some(anim_sets), Some(items)) = (maps.get(&map_asset.map), class_lists.get(&class_asset.classes), animation_sets.get(&animation_asset.sets), item_lists.get(&item_asset.items)) else
+    {
+        panic!("Battle data isn't loaded! Units can't be spawned.")
+    };
+    for me in spawn_map_units(&mut cmd, map, classes, anim_sets, items, &mut sprite_params)
+    {
+        update_unit_render_location.send(UpdateUnitRenderLocation(me));
+    }
 }
 
+///Spawn the units a map starts with, exactly as they are at the start of the battle.
+pub fn spawn_map_units
+(
+    cmd: &mut Commands,
+    map: &MapData,
+    classes: &ClassList,
+    anim_sets: &AnimationSetList,
+    items: &ItemList,
+    sprite_params: &mut Sprite3dParams
+) -> Vec<Entity>
+{
+    let mut spawned = Vec::new();
+    let mut team_sizes: HashMap<u32, u32> = HashMap::new();
+    for spawn in &map.units
+    {
+        let Some(class) = classes.get(&spawn.class) else
+        {
+            println!("Class {} of {} doesn't exist. Unit not spawned.", spawn.class, spawn.name);
+            continue
+        };
+        let sprite = spawn.sprite.as_ref().unwrap_or(&class.animation_set);
+        let Some(anim_set) = anim_sets.get(sprite) else
+        {
+            println!("Animation set {} of {} doesn't exist. Unit not spawned.", sprite, spawn.name);
+            continue
+        };
+        let mut inventory = Inventory::default();
+        for name in &spawn.items
+        {
+            match items.get(name)
+            {
+                Some(weapon) => inventory.add(Item::new(weapon)).unwrap_or_else(|_| println!("{} can't carry {}.", spawn.name, name)),
+                None => println!("Item {} of {} doesn't exist. Item not given.", name, spawn.name),
+            }
+        }
+        let id = team_sizes.entry(spawn.team).or_insert(0);
+        let me = spawn_unit(cmd, anim_set, sprite_params, UnitBundle
+        {
+            is_unit: IsUnit,
+            unit_name: ObjName(spawn.name.clone()),
+            unit_id: UnitID{team: spawn.team, id: *id},
+            team: Team(spawn.team),
+            movement: Movement(class.movement),
+            health: Health
+            {
+                ..default()
+            },
+            stats: spawn.stats.unwrap_or(class.base),
+            class: Class(class.clone()),
+            loc: Location(spawn.location.0, spawn.location.1),
+            sprite: Sprite(anim_set.name.clone()),
+            inventory,
+            experience: Experience{level: spawn.level, exp: 0},
+            growths: PersonalGrowths(spawn.growths.unwrap_or_default()),
+        });
+        *id += 1;
+        if spawn.lord
+        {
+            cmd.entity(me).insert(Lord);
+        }
+        if spawn.boss
+        {
+            cmd.entity(me).insert(Boss);
+        }
+        spawned.push(me);
+    }
+    spawned
+}
 
-pub fn init_unit_model
+///Spawn a unit along with its sprite model and animations.
+pub fn spawn_unit
 (
-    mut cmd: Commands, 
-    image_server: Res<ImageAsset>,
-    //mut meshs: ResMut<Assets<Mesh>>, 
-    //mut materials: ResMut<Assets<StandardMaterial>>,
-    mut update_unit_render_location: EventWriter<UpdateUnitRenderLocation>,
-    mut sprite_params: Sprite3dParams,
-)
+    cmd: &mut Commands,
+    anim_set: &AnimationSet,
+    sprite_params: &mut Sprite3dParams,
+    unit: UnitBundle
+) -> Entity
 {
-    let sprite_name: String = "PlaceholderMSprite.png".into();
     let atlas = TextureAtlas 
     {
-        layout: image_server.layout.clone(),
+        layout: anim_set.layout.clone(),
         index: 0,
     };
 
-    let mut ani_hash: HashMap<String, Vec<usize>> = HashMap::new();
-    ani_hash.insert("idle".into(), vec![0,0,0,0,0,1,2,2,2,2,2,1]);
-    ani_hash.insert("running_lr".into(), (4..8).collect());
-    ani_hash.insert("running_down".into(), (8..12).collect());
-    ani_hash.insert("running_up".into(), (12..16).collect());
-    ani_hash.insert("selected".into(), vec![16,16,16,16,16,17,18,18,18,18,18,17]);
-
     let ani_lib: AnimationLibrary = AnimationLibraryBuilder::new()
-        .set_animations(ani_hash)
-        .set_frames_per(12)
-        .set_animation("running_lr".into())
+        .set_animations(anim_set.animations.clone())
+        .set_animation(ClipId::RunningLr)
         .build();
-    let me = cmd.spawn((UnitBundle
+    cmd.spawn((unit,
+    /*
+    model: PbrBundle
     {
-        is_unit: IsUnit,
-        unit_name: ObjName("Martin".into()),
-        team: Team(0),
-        movement: Movement(5.0),
-        health: Health
-        {
-            ..default()
-        },
-        loc: Location(7, 9),
-        sprite: Sprite(sprite_name),
-        /*
-        model: PbrBundle
-        {
-            mesh: meshs.add(Cuboid::new(0.9, 0.9, 0.9)),
-            material: materials.add(StandardMaterial {
-                base_color_texture: Some(pic),
-                ..default()
-            }),
-            transform: Transform{translation: Vec3::new(0.0, 1.1, 0.0), rotation: Quat::from_axis_angle(Vec3::Y, PI), scale: Vec3::ONE},
+        mesh: meshs.add(Cuboid::new(0.9, 0.9, 0.9)),
+        material: materials.add(StandardMaterial {
+            base_color_texture: Some(pic),
             ..default()
-        }
-        */
-    },
+        }),
+        transform: Transform{translation: Vec3::new(0.0, 1.1, 0.0), rotation: Quat::from_axis_angle(Vec3::Y, PI), scale: Vec3::ONE},
+        ..default()
+    }
+    */
     Sprite3d
     {
-        image: image_server.image.clone(),
+        image: anim_set.image.clone(),
         pixels_per_metre: 16.,
 
         ..default()
-    }.bundle_with_atlas(&mut sprite_params, atlas),
+    }.bundle_with_atlas(sprite_params, atlas),
     FaceCamera,
 
-    AnimationLibrary
-    {
-        animations: ani_lib.animations,
-        current_animation: ani_lib.current_animation,
-        current_frame: ani_lib.current_frame,
-        timer: ani_lib.timer,
-    },
+    ani_lib,
 
     /*
     TestTimer
@@ -318,44 +413,58 @@ pub fn init_unit_model
         timer: Timer::new(Duration::from_secs(2), TimerMode::Repeating)
     }
     */
-    )).id();
-    update_unit_render_location.send(UpdateUnitRenderLocation(me));
+    )).id()
 }
 
-pub fn animate_sprites
-(
-    time: Res<Time>,
-    mut query: Query<(&mut AnimationLibrary, &mut TextureAtlas)>,
-) 
-{
-    for (mut animation, mut atlas) in query.iter_mut() 
-    {
-        animation.timer.tick(time.delta());
-
-        if animation.timer.just_finished() 
-        {
-            let ani_length: usize;
-            if let Some(cur_ani) = animation.animations.get(&animation.current_animation)
-            {
-                atlas.index = cur_ani[animation.current_frame];
-                ani_length = cur_ani.len();
-            } else {panic!("Missing {} animation!", animation.current_animation)};
-            animation.current_frame += 1;
-            animation.current_frame %= ani_length;
-        }
-    }
-}
 /// updates units rendered location to match internal location
 /// 
 /// todo Probably needs to be replaced with a dedicated rendering/animation module
 pub fn update_render_location
 (
     mut qry: Query<(&mut Transform, &Location), (With<ObjName>, Changed<Location>)>,
+    height_qry: Query<&HeightMap>
 )
 {
+    let Ok(heights) = height_qry.get_single() else {return};
     for (mut transform, loc) in qry.iter_mut()
     {
-        transform.translation = Vec3::new(loc.0 as f32, 1.1, loc.1 as f32)
+        transform.translation = unit_translation(*loc, heights.at(*loc))
+    }
+}
+
+///Move walking units along their path and commit their location once they arrive.
+pub fn walk_units
+(
+    mut cmd: Commands,
+    time: Res<Time>,
+    mut unit_qry: Query<(Entity, &mut WalkPath, &mut Transform, &mut Location, &mut AnimationLibrary)>,
+    height_qry: Query<&HeightMap>,
+    mut walk_finished: EventWriter<WalkFinished>
+)
+{
+    let Ok(heights) = height_qry.get_single() else {return};
+    let translation = |loc: Location| unit_translation(loc, heights.at(loc));
+    for (unit, mut walk, mut transform, mut loc, mut ani_lib) in unit_qry.iter_mut()
+    {
+        walk.progress += time.delta_seconds() * WALK_SPEED;
+        while walk.progress >= 1.0 && !walk.steps.is_empty()
+        {
+            walk.progress -= 1.0;
+            walk.from = walk.steps.pop_front().unwrap();
+        }
+
+        let Some(&next) = walk.steps.front() else
+        {
+            *loc = walk.from;
+            transform.translation = translation(walk.from);
+            ani_lib.set_animation(walk.resume_animation);
+            cmd.entity(unit).remove::<WalkPath>();
+            walk_finished.send(WalkFinished(unit));
+            continue;
+        };
+
+        ani_lib.set_animation(step_animation(walk.from, next));
+        transform.translation = translation(walk.from).lerp(translation(next), walk.progress);
     }
 }
 
@@ -383,20 +492,30 @@ pub fn test_move_one_right
 //todo Solve interdependcy issues- put into shared, put all components in shared, create "intergration module"?
 //todo Move UpdateUnitRenderLocation calls in here so they happen automatically, though it should be replaced
 //todo eventually with a dedicated rendering/animation module
+///This is the only system that should write to the UnitMap. Units move by changing their Location, and get taken off the map when despawned.
 pub fn synch_unit_map
 (
     unit_qry: Query<(&Location, Entity), (Changed<Location>, With<IsUnit>)>,
+    mut removed_units: RemovedComponents<IsUnit>,
     mut unit_map_qry: Query<&mut UnitMap>
 )
 {
+    let Ok(mut map) = unit_map_qry.get_single_mut() else {return};
+    for unit in removed_units.read()
+    {
+        map.remove(unit);
+    }
     for (&unit_loc, unit) in &unit_qry
     {
-        if let Ok(mut map) = unit_map_qry.get_single_mut()
+        let result = match map.location_of(unit)
         {
-            map[unit_loc.1][unit_loc.0] = Some(unit);
-        } else {panic!("AY! Where'd my unit map go, eh?")}
-
-
+            Some(_) => map.move_unit(unit, unit_loc).map(|_| ()),
+            None => map.place(unit, unit_loc),
+        };
+        if let Err(err) = result
+        {
+            println!("Couldn't put {:?} on the unit map at {:?}: {:?}", unit, unit_loc, err);
+        }
     }
 }
 